@@ -0,0 +1,40 @@
+use std::collections::HashSet;
+
+/// Origins allowed to call a server from a browser.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum AllowList {
+    Any,
+    List(HashSet<String>),
+}
+
+impl AllowList {
+    pub(crate) fn default_local() -> Self {
+        let mut set = HashSet::new();
+        set.insert("http://localhost".to_owned());
+        set.insert("http://127.0.0.1".to_owned());
+        AllowList::List(set)
+    }
+
+    /// `*` anywhere in the list allows any origin; blank entries are ignored.
+    pub(crate) fn from_origins(origins: Vec<String>) -> Self {
+        if origins.iter().any(|value| value == "*") {
+            return AllowList::Any;
+        }
+        let set = origins
+            .into_iter()
+            .filter(|value| !value.trim().is_empty())
+            .collect::<HashSet<_>>();
+        AllowList::List(set)
+    }
+
+    pub(crate) fn allows(&self, origin: &str) -> bool {
+        match self {
+            AllowList::Any => true,
+            AllowList::List(list) => list.contains(origin),
+        }
+    }
+
+    pub(crate) fn is_any(&self) -> bool {
+        matches!(self, AllowList::Any)
+    }
+}
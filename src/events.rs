@@ -0,0 +1,70 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use addin1c::{name, CString1C};
+
+const EVENT_QUEUE_RETRY_MS: u64 = 10;
+/// How long a WebSocket side that sent a close frame waits for the peer's answer.
+pub(crate) const CLOSE_HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+/// Raises an external event; returns `false` when the 1C event queue is full.
+pub(crate) type EventSink = Arc<dyn Fn(&str, serde_json::Value) -> bool + Send + Sync>;
+
+/// Raises events as `WebTransport` external events of 1C.
+pub(crate) fn connection_sink(
+    connection: Option<&'static addin1c::Connection>,
+) -> Option<EventSink> {
+    connection.map(|connection| {
+        Arc::new(move |event: &str, payload: serde_json::Value| {
+            let data = CString1C::from(payload.to_string().as_str());
+            let event = CString1C::from(event);
+            connection.external_event(name!("WebTransport"), event, data)
+        }) as EventSink
+    })
+}
+
+/// The 1C event buffer is bounded: wait for room instead of dropping the message,
+/// which also stops reading from the socket until 1C catches up.
+pub(crate) async fn deliver_event<F>(emit: &F, event: &str, payload: serde_json::Value)
+where
+    F: Fn(&str, serde_json::Value) -> bool + ?Sized,
+{
+    while !emit(event, payload.clone()) {
+        tokio::time::sleep(Duration::from_millis(EVENT_QUEUE_RETRY_MS)).await;
+    }
+}
+
+/// Raises an event if 1C accepts events at all; without `wait_for_queue` an event
+/// that does not fit into the 1C queue is dropped.
+pub(crate) async fn notify(
+    events: Option<&EventSink>,
+    event: &str,
+    payload: serde_json::Value,
+    wait_for_queue: bool,
+) {
+    let Some(emit) = events else {
+        return;
+    };
+    if wait_for_queue {
+        deliver_event(emit.as_ref(), event, payload).await;
+    } else {
+        emit(event, payload);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn deliver_event_retries_while_queue_is_full() {
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let emit = |_: &str, _: serde_json::Value| {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::Relaxed) >= 2
+        };
+
+        deliver_event(&emit, "WS_MESSAGE", serde_json::Value::Null).await;
+
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::Relaxed), 3);
+    }
+}
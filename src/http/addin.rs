@@ -1,12 +1,17 @@
 use std::collections::HashMap;
 use std::error::Error;
-use std::sync::{atomic::AtomicU64, Arc};
+use std::sync::{atomic::AtomicU64, Arc, RwLock};
 
 use addin1c::{name, AddinResult, CStr1C, MethodInfo, Methods, PropInfo, SimpleAddin, Variant};
 use tokio::runtime::Runtime;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::Mutex;
 
+use super::auth::AuthPolicy;
+use super::cors::CorsPolicy;
+use super::routes::RouteTable;
 use super::server::HttpServerState;
+use super::sse_handler::SseSessions;
+use super::ws_handler::WsSessions;
 use crate::addin_error::report_platform_error;
 use crate::VERSION;
 
@@ -15,8 +20,13 @@ pub struct HttpAddIn {
     pub(super) runtime: Arc<Runtime>,
     pub(super) http_server: Option<HttpServerState>,
     pub(super) http_request_counter: Arc<AtomicU64>,
-    pub(super) sse_sessions: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>,
+    pub(super) sse_sessions: SseSessions,
     pub(super) sse_session_counter: Arc<AtomicU64>,
+    pub(super) ws_sessions: WsSessions,
+    pub(super) ws_session_counter: Arc<AtomicU64>,
+    pub(super) routes: Arc<RwLock<RouteTable>>,
+    pub(super) auth: Arc<RwLock<Option<AuthPolicy>>>,
+    pub(super) cors: Arc<RwLock<CorsPolicy>>,
     last_error: Option<Box<dyn Error>>,
 }
 
@@ -59,24 +69,88 @@ impl SimpleAddin for HttpAddIn {
         &[
             MethodInfo {
                 name: name!("ЗапуститьHTTP"),
-                method: Methods::Method1(Self::http_start),
+                method: Methods::Method2(Self::http_start),
+            },
+            MethodInfo {
+                name: name!("ОбновитьСертификат"),
+                method: Methods::Method2(Self::http_reload_certificate),
             },
             MethodInfo {
                 name: name!("ОстановитьHTTP"),
                 method: Methods::Method0(Self::http_stop),
             },
+            MethodInfo {
+                name: name!("УстановитьАвторизацию"),
+                method: Methods::Method1(Self::set_auth),
+            },
+            MethodInfo {
+                name: name!("УстановитьCORS"),
+                method: Methods::Method1(Self::set_cors),
+            },
+            MethodInfo {
+                name: name!("ДобавитьМаршрут"),
+                method: Methods::Method2(Self::route_add),
+            },
+            MethodInfo {
+                name: name!("УдалитьМаршрут"),
+                method: Methods::Method2(Self::route_remove),
+            },
             MethodInfo {
                 name: name!("ОтправитьHTTPОтвет"),
                 method: Methods::Method4(Self::http_send_response),
             },
+            MethodInfo {
+                name: name!("ОтправитьHTTPОтветДвоичный"),
+                method: Methods::Method4(Self::http_send_binary_response),
+            },
+            MethodInfo {
+                name: name!("НачатьHTTPОтвет"),
+                method: Methods::Method3(Self::http_begin_response),
+            },
+            MethodInfo {
+                name: name!("ОтправитьHTTPЧасть"),
+                method: Methods::Method2(Self::http_send_chunk),
+            },
+            MethodInfo {
+                name: name!("ЗавершитьHTTPОтвет"),
+                method: Methods::Method1(Self::http_end_response),
+            },
+            MethodInfo {
+                name: name!("ПродлитьОжиданиеОтвета"),
+                method: Methods::Method2(Self::http_extend_deadline),
+            },
             MethodInfo {
                 name: name!("ОтправитьSSE"),
-                method: Methods::Method2(Self::sse_send),
+                method: Methods::Method5(Self::sse_send),
+            },
+            MethodInfo {
+                name: name!("ОтправитьSSEВКанал"),
+                method: Methods::Method5(Self::sse_send_to_channel),
+            },
+            MethodInfo {
+                name: name!("ОтправитьSSEВсем"),
+                method: Methods::Method4(Self::sse_broadcast),
+            },
+            MethodInfo {
+                name: name!("ПодписатьSSEНаКанал"),
+                method: Methods::Method2(Self::sse_join),
+            },
+            MethodInfo {
+                name: name!("ОтписатьSSEОтКанала"),
+                method: Methods::Method2(Self::sse_leave),
             },
             MethodInfo {
                 name: name!("ЗакрытьSSE"),
                 method: Methods::Method1(Self::sse_close),
             },
+            MethodInfo {
+                name: name!("ОтправитьWS"),
+                method: Methods::Method2(Self::ws_send),
+            },
+            MethodInfo {
+                name: name!("ЗакрытьWS"),
+                method: Methods::Method1(Self::ws_close),
+            },
             MethodInfo {
                 name: name!("Версия"),
                 method: Methods::Method0(Self::version),
@@ -100,8 +174,13 @@ impl Default for HttpAddIn {
             last_error: None,
             http_server: None,
             http_request_counter: Arc::new(AtomicU64::new(1)),
-            sse_sessions: Arc::new(Mutex::new(HashMap::new())),
+            sse_sessions: SseSessions::default(),
             sse_session_counter: Arc::new(AtomicU64::new(1)),
+            ws_sessions: Arc::new(Mutex::new(HashMap::new())),
+            ws_session_counter: Arc::new(AtomicU64::new(1)),
+            routes: Arc::new(RwLock::new(RouteTable::default())),
+            auth: Arc::new(RwLock::new(None)),
+            cors: Arc::new(RwLock::new(CorsPolicy::default())),
             runtime: Arc::new(Runtime::new().unwrap()),
         }
     }
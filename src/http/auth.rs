@@ -0,0 +1,524 @@
+use std::error::Error;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use axum::http::HeaderMap;
+use base64::engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL};
+use base64::Engine;
+use ring::{hmac, signature};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::SubjectPublicKeyInfoDer;
+use serde_json::{Map, Value};
+
+use crate::{option_str, option_u64};
+
+const DEFAULT_API_KEY_HEADER: &str = "x-api-key";
+const DEFAULT_JWT_LEEWAY_SECS: u64 = 60;
+
+/// Authentication policy of the http object, parsed from `УстановитьАвторизацию`.
+/// A request passes if any of the configured methods accepts it.
+pub(super) struct AuthPolicy {
+    api_key_header: String,
+    api_keys: Vec<Credential>,
+    basic: Vec<(String, String)>,
+    bearer: Vec<Credential>,
+    jwt: Option<JwtValidator>,
+}
+
+/// A secret with the principal name reported to 1C.
+struct Credential {
+    secret: String,
+    name: Option<String>,
+}
+
+struct JwtValidator {
+    key: JwtKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+}
+
+enum JwtKey {
+    Hs256(hmac::Key),
+    /// DER `RSAPublicKey`.
+    Rs256(Vec<u8>),
+}
+
+impl AuthPolicy {
+    pub(super) fn from_json(value: &Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект".to_owned())?;
+
+        let api_key_header = option_str(obj, "apiKeyHeader")?
+            .unwrap_or(DEFAULT_API_KEY_HEADER)
+            .to_ascii_lowercase();
+        let api_keys = credentials(obj, "apiKeys", "key")?;
+        let bearer = credentials(obj, "bearer", "token")?;
+        let basic = match obj.get("basic") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| {
+                    let item = item
+                        .as_object()
+                        .ok_or_else(|| "Элементы basic должны быть объектами".to_owned())?;
+                    let user = option_str(item, "user")?
+                        .ok_or_else(|| "Не задан user в параметре basic".to_owned())?;
+                    let password = option_str(item, "password")?.unwrap_or_default();
+                    Ok((user.to_owned(), password.to_owned()))
+                })
+                .collect::<Result<_, Box<dyn Error>>>()?,
+            Some(_) => return Err("Параметр basic должен быть массивом".into()),
+        };
+        let jwt = match obj.get("jwt") {
+            None | Some(Value::Null) => None,
+            Some(value) => Some(JwtValidator::from_json(value)?),
+        };
+
+        let policy = Self {
+            api_key_header,
+            api_keys,
+            basic,
+            bearer,
+            jwt,
+        };
+        if policy.api_keys.is_empty()
+            && policy.basic.is_empty()
+            && policy.bearer.is_empty()
+            && policy.jwt.is_none()
+        {
+            return Err("Не задан ни один способ авторизации".into());
+        }
+        Ok(policy)
+    }
+
+    /// Returns the principal for the request JSON, or `None` if the request is rejected.
+    pub(super) fn authenticate(&self, headers: &HeaderMap) -> Option<Value> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+
+        if let Some(key) = header(&self.api_key_header) {
+            if let Some(credential) = find(&self.api_keys, key) {
+                return Some(principal("apiKey", credential.name.as_deref()));
+            }
+        }
+
+        let authorization = header("authorization")?;
+        let (scheme, value) = authorization.split_once(' ')?;
+        let value = value.trim();
+        if scheme.eq_ignore_ascii_case("basic") {
+            let decoded = BASE64.decode(value).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (user, password) = decoded.split_once(':')?;
+            let mut matched = false;
+            for (expected_user, expected_password) in &self.basic {
+                matched |= constant_time_eq(user, expected_user)
+                    & constant_time_eq(password, expected_password);
+            }
+            return matched.then(|| principal("basic", Some(user)));
+        }
+        if scheme.eq_ignore_ascii_case("bearer") {
+            if let Some(credential) = find(&self.bearer, value) {
+                return Some(principal("bearer", credential.name.as_deref()));
+            }
+            let claims = self.jwt.as_ref()?.validate(value)?;
+            let mut principal = principal("jwt", claims.get("sub").and_then(Value::as_str));
+            principal["claims"] = Value::Object(claims);
+            return Some(principal);
+        }
+        None
+    }
+
+    /// Value of `WWW-Authenticate` for the 401 response.
+    pub(super) fn challenge(&self) -> String {
+        let mut schemes = Vec::new();
+        if !self.basic.is_empty() {
+            schemes.push("Basic realm=\"1C\", charset=\"UTF-8\"");
+        }
+        if !self.bearer.is_empty() || self.jwt.is_some() {
+            schemes.push("Bearer");
+        }
+        if schemes.is_empty() {
+            schemes.push("ApiKey");
+        }
+        schemes.join(", ")
+    }
+}
+
+impl JwtValidator {
+    fn from_json(value: &Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект в параметре jwt".to_owned())?;
+
+        let key = match option_str(obj, "algorithm")? {
+            Some("HS256") => {
+                let secret = option_str(obj, "secret")?
+                    .ok_or_else(|| "Для HS256 нужен параметр secret".to_owned())?;
+                JwtKey::Hs256(hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()))
+            }
+            Some("RS256") => {
+                let public_key = option_str(obj, "publicKey")?
+                    .ok_or_else(|| "Для RS256 нужен параметр publicKey".to_owned())?;
+                JwtKey::Rs256(load_rsa_public_key(public_key)?)
+            }
+            _ => return Err("Параметр algorithm должен быть HS256 или RS256".into()),
+        };
+
+        Ok(Self {
+            key,
+            issuer: option_str(obj, "issuer")?.map(str::to_owned),
+            audience: option_str(obj, "audience")?.map(str::to_owned),
+            leeway: option_u64(obj, "leewaySecs")?.unwrap_or(DEFAULT_JWT_LEEWAY_SECS),
+        })
+    }
+
+    /// Checks the signature with the configured algorithm only, then `exp`, `nbf`,
+    /// `iss` and `aud`; returns the claims.
+    fn validate(&self, token: &str) -> Option<Map<String, Value>> {
+        let mut parts = token.split('.');
+        let (header, payload, signature) = (parts.next()?, parts.next()?, parts.next()?);
+        if parts.next().is_some() {
+            return None;
+        }
+
+        let header: Value = serde_json::from_slice(&BASE64_URL.decode(header).ok()?).ok()?;
+        let signed = &token[..token.rfind('.')?];
+        let signature = BASE64_URL.decode(signature).ok()?;
+        let verified = match (&self.key, header["alg"].as_str()?) {
+            (JwtKey::Hs256(key), "HS256") => {
+                hmac::verify(key, signed.as_bytes(), &signature).is_ok()
+            }
+            (JwtKey::Rs256(key), "RS256") => {
+                signature::UnparsedPublicKey::new(&signature::RSA_PKCS1_2048_8192_SHA256, key)
+                    .verify(signed.as_bytes(), &signature)
+                    .is_ok()
+            }
+            _ => false,
+        };
+        if !verified {
+            return None;
+        }
+
+        let claims: Map<String, Value> =
+            serde_json::from_slice(&BASE64_URL.decode(payload).ok()?).ok()?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let time = |name: &str| claims.get(name).map(Value::as_u64);
+        match time("exp") {
+            Some(Some(exp)) if exp.saturating_add(self.leeway) <= now => return None,
+            Some(None) => return None,
+            _ => {}
+        }
+        match time("nbf") {
+            Some(Some(nbf)) if nbf > now.saturating_add(self.leeway) => return None,
+            Some(None) => return None,
+            _ => {}
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer) {
+                return None;
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let matches = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(items)) => items.iter().any(|aud| aud == audience),
+                _ => false,
+            };
+            if !matches {
+                return None;
+            }
+        }
+        Some(claims)
+    }
+}
+
+/// Reads a list of plain strings or of objects with the secret under `key` and an
+/// optional `name`.
+fn credentials(
+    obj: &Map<String, Value>,
+    field: &str,
+    key: &str,
+) -> Result<Vec<Credential>, Box<dyn Error>> {
+    let invalid = || format!("Параметр {field} должен быть массивом строк или объектов");
+    match obj.get(field) {
+        None | Some(Value::Null) => Ok(Vec::new()),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| match item {
+                Value::String(secret) if !secret.is_empty() => Ok(Credential {
+                    secret: secret.clone(),
+                    name: None,
+                }),
+                Value::Object(item) => Ok(Credential {
+                    secret: option_str(item, key)?
+                        .ok_or_else(|| format!("Не задан {key} в параметре {field}"))?
+                        .to_owned(),
+                    name: option_str(item, "name")?.map(str::to_owned),
+                }),
+                _ => Err(invalid().into()),
+            })
+            .collect(),
+        Some(_) => Err(invalid().into()),
+    }
+}
+
+fn find<'a>(credentials: &'a [Credential], secret: &str) -> Option<&'a Credential> {
+    let mut found = None;
+    for credential in credentials {
+        if constant_time_eq(secret, &credential.secret) && found.is_none() {
+            found = Some(credential);
+        }
+    }
+    found
+}
+
+fn principal(scheme: &str, name: Option<&str>) -> Value {
+    serde_json::json!({ "scheme": scheme, "name": name })
+}
+
+/// Compares secrets without an early exit, so the timing does not reveal the prefix.
+fn constant_time_eq(left: &str, right: &str) -> bool {
+    let (left, right) = (left.as_bytes(), right.as_bytes());
+    let mut diff = left.len() ^ right.len();
+    for (index, byte) in left.iter().enumerate() {
+        diff |= usize::from(byte ^ right.get(index).copied().unwrap_or(0));
+    }
+    diff == 0
+}
+
+/// Accepts a `PUBLIC KEY` PEM (text or path) and extracts the `RSAPublicKey` that
+/// `ring` expects from its `SubjectPublicKeyInfo`.
+fn load_rsa_public_key(value: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let pem = match value.contains("-----BEGIN") {
+        true => value.as_bytes().to_vec(),
+        false => std::fs::read(value)
+            .map_err(|err| format!("Не удалось прочитать файл {value}: {err}"))?,
+    };
+    let spki = SubjectPublicKeyInfoDer::from_pem_slice(&pem)
+        .map_err(|err| format!("Некорректный PEM открытого ключа: {err}"))?;
+    spki_public_key(&spki).map(<[u8]>::to_vec)
+}
+
+/// `rsaEncryption` (1.2.840.113549.1.1.1) as DER object identifier contents.
+const RSA_ENCRYPTION_OID: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+
+/// `SubjectPublicKeyInfo ::= SEQUENCE { algorithm SEQUENCE, subjectPublicKey BIT STRING }`
+fn spki_public_key(der: &[u8]) -> Result<&[u8], Box<dyn Error>> {
+    let invalid = || "Некорректный открытый ключ RSA";
+    let (tag, spki, _) = der_element(der).ok_or_else(invalid)?;
+    if tag != 0x30 {
+        return Err(invalid().into());
+    }
+    let (tag, algorithm, rest) = der_element(spki).ok_or_else(invalid)?;
+    if tag != 0x30 {
+        return Err(invalid().into());
+    }
+    match der_element(algorithm) {
+        Some((0x06, RSA_ENCRYPTION_OID, _)) => {}
+        Some((0x06, _, _)) => {
+            return Err("Открытый ключ не является ключом RSA (rsaEncryption)".into())
+        }
+        _ => return Err(invalid().into()),
+    }
+    let (tag, bits, _) = der_element(rest).ok_or_else(invalid)?;
+    match (tag, bits.split_first()) {
+        (0x03, Some((0, key))) => Ok(key),
+        _ => Err(invalid().into()),
+    }
+}
+
+/// Splits a DER element into its tag, contents and the bytes after it.
+fn der_element(der: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = der.split_first()?;
+    let (&first, rest) = rest.split_first()?;
+    let (len, rest) = match first {
+        0..=0x7f => (usize::from(first), rest),
+        0x81..=0x84 => {
+            let count = usize::from(first & 0x7f);
+            if rest.len() < count {
+                return None;
+            }
+            let (bytes, rest) = rest.split_at(count);
+            let len = bytes
+                .iter()
+                .fold(0usize, |len, byte| (len << 8) | usize::from(*byte));
+            (len, rest)
+        }
+        _ => return None,
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (contents, rest) = rest.split_at(len);
+    Some((tag, contents, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+
+    const RSA_KEY: &str = include_str!("testdata/jwt_rsa_key.pem");
+    const RSA_PUBLIC_KEY: &str = include_str!("testdata/jwt_rsa_pub.pem");
+    const EC_PUBLIC_KEY: &str = include_str!("testdata/jwt_ec_pub.pem");
+
+    fn policy(value: Value) -> AuthPolicy {
+        AuthPolicy::from_json(&value).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_str(value).unwrap());
+        }
+        headers
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn jwt(alg: &str, claims: Value, sign: impl Fn(&[u8]) -> Vec<u8>) -> String {
+        let header = BASE64_URL.encode(serde_json::json!({ "alg": alg, "typ": "JWT" }).to_string());
+        let payload = BASE64_URL.encode(claims.to_string());
+        let signed = format!("{header}.{payload}");
+        let signature = BASE64_URL.encode(sign(signed.as_bytes()));
+        format!("{signed}.{signature}")
+    }
+
+    fn hs256(secret: &str, claims: Value) -> String {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+        jwt("HS256", claims, |data| {
+            hmac::sign(&key, data).as_ref().to_vec()
+        })
+    }
+
+    fn rs256(claims: Value) -> String {
+        let der = PrivatePkcs8KeyDer::from_pem_slice(RSA_KEY.as_bytes()).unwrap();
+        let key = signature::RsaKeyPair::from_pkcs8(der.secret_pkcs8_der()).unwrap();
+        jwt("RS256", claims, |data| {
+            let mut signature = vec![0; key.public().modulus_len()];
+            let rng = ring::rand::SystemRandom::new();
+            key.sign(&signature::RSA_PKCS1_SHA256, &rng, data, &mut signature)
+                .unwrap();
+            signature
+        })
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        headers(&[("authorization", &format!("Bearer {token}"))])
+    }
+
+    #[test]
+    fn accepts_api_keys_basic_and_static_bearer_tokens() {
+        let policy = policy(serde_json::json!({
+            "apiKeys": ["k1", { "key": "k2", "name": "crm" }],
+            "basic": [{ "user": "admin", "password": "secret" }],
+            "bearer": [{ "token": "t1", "name": "bot" }],
+        }));
+
+        assert_eq!(
+            policy.authenticate(&headers(&[("x-api-key", "k2")])),
+            Some(serde_json::json!({ "scheme": "apiKey", "name": "crm" }))
+        );
+        assert_eq!(
+            policy.authenticate(&headers(&[("x-api-key", "k1")])),
+            Some(serde_json::json!({ "scheme": "apiKey", "name": null }))
+        );
+        assert_eq!(
+            policy.authenticate(&headers(&[("authorization", "Basic YWRtaW46c2VjcmV0")])),
+            Some(serde_json::json!({ "scheme": "basic", "name": "admin" }))
+        );
+        assert_eq!(
+            policy.authenticate(&bearer("t1")),
+            Some(serde_json::json!({ "scheme": "bearer", "name": "bot" }))
+        );
+
+        for rejected in [
+            headers(&[]),
+            headers(&[("x-api-key", "k3")]),
+            headers(&[("authorization", "Basic YWRtaW46d3Jvbmc=")]),
+            bearer("t2"),
+        ] {
+            assert_eq!(policy.authenticate(&rejected), None);
+        }
+        assert_eq!(
+            policy.challenge(),
+            "Basic realm=\"1C\", charset=\"UTF-8\", Bearer"
+        );
+    }
+
+    #[test]
+    fn validates_hs256_tokens() {
+        let policy = policy(serde_json::json!({
+            "jwt": { "algorithm": "HS256", "secret": "s3cret", "issuer": "idp", "audience": "1c" },
+        }));
+        let claims = serde_json::json!({
+            "sub": "user-1",
+            "iss": "idp",
+            "aud": ["1c", "other"],
+            "exp": now() + 60,
+        });
+
+        let principal = policy.authenticate(&bearer(&hs256("s3cret", claims.clone())));
+        let principal = principal.expect("token should pass");
+        assert_eq!(principal["scheme"], "jwt");
+        assert_eq!(principal["name"], "user-1");
+        assert_eq!(principal["claims"], claims);
+
+        let mut expired = claims.clone();
+        expired["exp"] = (now() - 120).into();
+        let mut foreign = claims.clone();
+        foreign["iss"] = "other".into();
+        for token in [
+            hs256("wrong", claims.clone()),
+            hs256("s3cret", expired),
+            hs256("s3cret", foreign),
+            jwt("none", claims.clone(), |_| Vec::new()),
+            "not.a.token".to_owned(),
+        ] {
+            assert_eq!(policy.authenticate(&bearer(&token)), None, "{token}");
+        }
+    }
+
+    #[test]
+    fn validates_rs256_tokens_with_the_algorithm_from_the_policy() {
+        let policy = policy(serde_json::json!({
+            "jwt": { "algorithm": "RS256", "publicKey": RSA_PUBLIC_KEY },
+        }));
+        let claims = serde_json::json!({ "sub": "svc", "exp": now() + 60 });
+
+        assert!(policy
+            .authenticate(&bearer(&rs256(claims.clone())))
+            .is_some());
+        // An HS256 token signed with the public key must not pass as RS256.
+        let forged = hs256(RSA_PUBLIC_KEY, claims);
+        assert_eq!(policy.authenticate(&bearer(&forged)), None);
+    }
+
+    #[test]
+    fn rejects_invalid_policies() {
+        for value in [
+            serde_json::json!({}),
+            serde_json::json!({ "apiKeys": "k1" }),
+            serde_json::json!({ "basic": [{ "password": "p" }] }),
+            serde_json::json!({ "jwt": { "algorithm": "HS512", "secret": "s" } }),
+            serde_json::json!({ "jwt": { "algorithm": "RS256", "publicKey": "-----BEGIN PUBLIC KEY-----\n-----END PUBLIC KEY-----" } }),
+        ] {
+            assert!(AuthPolicy::from_json(&value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn rejects_non_rsa_public_keys_for_rs256() {
+        let value = serde_json::json!({
+            "jwt": { "algorithm": "RS256", "publicKey": EC_PUBLIC_KEY },
+        });
+        let err = AuthPolicy::from_json(&value).err().unwrap();
+        assert!(err.to_string().contains("rsaEncryption"), "{err}");
+    }
+}
@@ -0,0 +1,328 @@
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+
+use axum::body::Body;
+use axum::extract::{Request, State};
+use axum::http::{header, HeaderName, HeaderValue, Method, Response, StatusCode};
+use axum::middleware::Next;
+use serde_json::{Map, Value};
+
+use crate::allow_list::AllowList;
+use crate::{option_bool, option_u64};
+
+const DEFAULT_METHODS: &[&str] = &["GET", "POST", "PUT", "PATCH", "DELETE", "OPTIONS"];
+const DEFAULT_HEADERS: &[&str] = &["content-type", "authorization"];
+const DEFAULT_MAX_AGE_SECS: u64 = 86400;
+
+/// CORS settings of `УстановитьCORS`. The default allows any origin without credentials.
+#[derive(Debug, Clone, PartialEq)]
+pub(super) struct CorsPolicy {
+    origins: AllowList,
+    methods: String,
+    headers: String,
+    expose_headers: Option<String>,
+    credentials: bool,
+    max_age: u64,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            origins: AllowList::Any,
+            methods: DEFAULT_METHODS.join(", "),
+            headers: DEFAULT_HEADERS.join(", "),
+            expose_headers: None,
+            credentials: false,
+            max_age: DEFAULT_MAX_AGE_SECS,
+        }
+    }
+}
+
+impl CorsPolicy {
+    pub(super) fn from_json(value: &Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект".to_owned())?;
+        let default = Self::default();
+
+        let origins = match obj.get("origins") {
+            None | Some(Value::Null) => default.origins,
+            Some(Value::String(value)) if value.trim() == "*" => AllowList::Any,
+            Some(Value::Array(_)) => AllowList::from_origins(string_list(obj, "origins")?),
+            Some(_) => {
+                return Err("Параметр origins должен быть \"*\" или массивом строк"
+                    .to_owned()
+                    .into())
+            }
+        };
+        let methods = match obj.get("methods") {
+            None | Some(Value::Null) => default.methods,
+            Some(_) => {
+                let mut methods = Vec::new();
+                for method in string_list(obj, "methods")? {
+                    let method = Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                        .map_err(|_| format!("Некорректный HTTP метод: {method}"))?;
+                    methods.push(method.as_str().to_owned());
+                }
+                methods.join(", ")
+            }
+        };
+        let headers = match obj.get("headers") {
+            None | Some(Value::Null) => default.headers,
+            Some(_) => header_list(obj, "headers")?,
+        };
+        let expose_headers = match obj.get("exposeHeaders") {
+            None | Some(Value::Null) => None,
+            Some(_) => Some(header_list(obj, "exposeHeaders")?).filter(|list| !list.is_empty()),
+        };
+
+        let credentials = option_bool(obj, "credentials")?.unwrap_or(default.credentials);
+        // With credentials the origin is echoed back, so `*` would let any site
+        // make authenticated requests.
+        if credentials && origins.is_any() {
+            return Err(
+                "Параметр credentials требует явного списка origins вместо \"*\""
+                    .to_owned()
+                    .into(),
+            );
+        }
+
+        Ok(Self {
+            origins,
+            methods,
+            headers,
+            expose_headers,
+            credentials,
+            max_age: option_u64(obj, "maxAgeSecs")?.unwrap_or(default.max_age),
+        })
+    }
+
+    /// Headers answering a request from `origin`; `None` when the origin is not allowed.
+    /// A list of origins (always the case with credentials) echoes the origin back.
+    fn response_headers(
+        &self,
+        origin: Option<&str>,
+        preflight: bool,
+    ) -> Option<Vec<(HeaderName, HeaderValue)>> {
+        let mut headers = Vec::new();
+        match origin {
+            Some(origin) if !self.origins.allows(origin) => return None,
+            Some(origin) if !self.origins.is_any() => {
+                let origin = HeaderValue::from_str(origin).ok()?;
+                headers.push((header::ACCESS_CONTROL_ALLOW_ORIGIN, origin));
+                headers.push((header::VARY, HeaderValue::from_static("Origin")));
+            }
+            Some(_) => headers.push((
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            )),
+            // Not a cross-origin browser request.
+            None => return Some(headers),
+        }
+        if self.credentials {
+            headers.push((
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            ));
+        }
+
+        let mut push = |name, value: &str| {
+            if let Ok(value) = HeaderValue::from_str(value) {
+                headers.push((name, value));
+            }
+        };
+        if preflight {
+            push(header::ACCESS_CONTROL_ALLOW_METHODS, &self.methods);
+            push(header::ACCESS_CONTROL_ALLOW_HEADERS, &self.headers);
+            push(header::ACCESS_CONTROL_MAX_AGE, &self.max_age.to_string());
+        } else if let Some(expose) = &self.expose_headers {
+            push(header::ACCESS_CONTROL_EXPOSE_HEADERS, expose);
+        }
+        Some(headers)
+    }
+}
+
+fn string_list(obj: &Map<String, Value>, key: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let error = || format!("Параметр {key} должен быть массивом строк");
+    obj.get(key)
+        .and_then(Value::as_array)
+        .ok_or_else(error)?
+        .iter()
+        .map(|value| value.as_str().map(str::trim).ok_or_else(error))
+        .filter(|value| !matches!(value, Ok("")))
+        .map(|value| value.map(str::to_owned).map_err(Into::into))
+        .collect()
+}
+
+fn header_list(obj: &Map<String, Value>, key: &str) -> Result<String, Box<dyn Error>> {
+    let list = string_list(obj, key)?;
+    for name in &list {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Некорректное имя заголовка: {name}"))?;
+    }
+    Ok(list.join(", "))
+}
+
+/// Middleware in front of every route: rejects disallowed origins with 403 before
+/// the request reaches 1C, answers preflights and adds CORS headers to responses.
+pub(super) async fn apply(
+    State(policy): State<Arc<RwLock<CorsPolicy>>>,
+    req: Request,
+    next: Next,
+) -> Response<Body> {
+    let origin = req
+        .headers()
+        .get(header::ORIGIN)
+        .map(|value| value.to_str().unwrap_or_default().to_owned());
+    let preflight = req.method() == Method::OPTIONS;
+    let headers = policy
+        .read()
+        .unwrap()
+        .response_headers(origin.as_deref(), preflight);
+    let Some(headers) = headers else {
+        return Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::from("Forbidden: Origin is not allowed"))
+            .unwrap();
+    };
+
+    let mut response = match preflight {
+        true => Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+        false => next.run(req).await,
+    };
+    let response_headers = response.headers_mut();
+    for (name, value) in headers {
+        match name == header::VARY {
+            true => response_headers.append(name, value),
+            false => response_headers.insert(name, value).is_some(),
+        };
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::get;
+    use axum::Router;
+    use tower::ServiceExt;
+
+    fn policy(raw: &str) -> CorsPolicy {
+        CorsPolicy::from_json(&serde_json::from_str(raw).unwrap()).unwrap()
+    }
+
+    async fn call(policy: CorsPolicy, method: Method, origin: Option<&str>) -> Response<Body> {
+        let policy = Arc::new(RwLock::new(policy));
+        let app = Router::new()
+            .route("/orders", get(|| async { "ok" }))
+            .layer(axum::middleware::from_fn_with_state(policy, apply));
+        let mut request = Request::builder().method(method).uri("/orders");
+        if let Some(origin) = origin {
+            request = request.header(header::ORIGIN, origin);
+        }
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn header_value(response: &Response<Body>, name: HeaderName) -> Option<&str> {
+        response
+            .headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap())
+    }
+
+    #[test]
+    fn parses_options() {
+        let parsed = policy(
+            r#"{"origins":["https://a.example"],"methods":["get","delete"],
+                "headers":["content-type","x-api-key"],"exposeHeaders":["x-total"],
+                "credentials":true,"maxAgeSecs":600}"#,
+        );
+        assert_eq!(
+            parsed,
+            CorsPolicy {
+                origins: AllowList::List(["https://a.example".to_owned()].into()),
+                methods: "GET, DELETE".to_owned(),
+                headers: "content-type, x-api-key".to_owned(),
+                expose_headers: Some("x-total".to_owned()),
+                credentials: true,
+                max_age: 600,
+            }
+        );
+        assert_eq!(policy("{}"), CorsPolicy::default());
+        assert_eq!(policy(r#"{"origins":["*"]}"#).origins, AllowList::Any);
+
+        for raw in [
+            r#"{"origins":"https://a.example"}"#,
+            r#"{"methods":["GE T"]}"#,
+            r#"{"headers":["bad header"]}"#,
+            r#"{"exposeHeaders":"x-total"}"#,
+            r#"{"maxAgeSecs":-1}"#,
+            // Credentials need an explicit list of origins.
+            r#"{"credentials":true}"#,
+            r#"{"origins":"*","credentials":true}"#,
+            r#"{"origins":["https://a.example","*"],"credentials":true}"#,
+            "[]",
+        ] {
+            let value = serde_json::from_str(raw).unwrap();
+            assert!(CorsPolicy::from_json(&value).is_err(), "{raw}");
+        }
+    }
+
+    #[tokio::test]
+    async fn default_policy_allows_any_origin() {
+        let response = call(CorsPolicy::default(), Method::OPTIONS, Some("https://x")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("*")
+        );
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_METHODS),
+            Some("GET, POST, PUT, PATCH, DELETE, OPTIONS")
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_origins_outside_the_list() {
+        let listed = policy(r#"{"origins":["https://a.example"],"credentials":true}"#);
+
+        let response = call(listed.clone(), Method::GET, Some("https://b.example")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let response = call(listed.clone(), Method::OPTIONS, Some("https://b.example")).await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let response = call(listed.clone(), Method::GET, None).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn echoes_allowed_origin_with_credentials() {
+        let listed = policy(
+            r#"{"origins":["https://a.example"],"credentials":true,"exposeHeaders":["x-total"]}"#,
+        );
+        let response = call(listed, Method::GET, Some("https://a.example")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_ORIGIN),
+            Some("https://a.example")
+        );
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_ALLOW_CREDENTIALS),
+            Some("true")
+        );
+        assert_eq!(
+            header_value(&response, header::ACCESS_CONTROL_EXPOSE_HEADERS),
+            Some("x-total")
+        );
+        assert_eq!(header_value(&response, header::VARY), Some("Origin"));
+    }
+}
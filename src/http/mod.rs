@@ -1,5 +1,12 @@
 mod addin;
+mod auth;
+mod cors;
 mod mcp_handler;
+mod options;
+mod routes;
 mod server;
+mod sse_handler;
+mod tls_listener;
+mod ws_handler;
 
 pub use addin::HttpAddIn;
@@ -0,0 +1,111 @@
+use std::error::Error;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::option_u64;
+use crate::tls::ServerTlsOptions;
+
+const DEFAULT_RESPONSE_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_SSE_KEEP_ALIVE_MS: u64 = 15_000;
+const DEFAULT_SSE_REPLAY_SIZE: usize = 100;
+
+/// Settings of `ЗапуститьHTTP`, parsed from its options JSON.
+pub(super) struct HttpOptions {
+    pub(super) tls: Option<ServerTlsOptions>,
+    /// How long a request waits for `ОтправитьHTTPОтвет`; `None` waits without limit.
+    pub(super) response_timeout: Option<Duration>,
+    /// Interval of keep-alive comments in SSE streams; `None` disables them.
+    pub(super) sse_keep_alive: Option<Duration>,
+    /// Events kept per SSE session for `Last-Event-ID` replay; `0` disables replay.
+    pub(super) sse_replay_size: usize,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            tls: None,
+            response_timeout: Some(Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS)),
+            sse_keep_alive: Some(Duration::from_millis(DEFAULT_SSE_KEEP_ALIVE_MS)),
+            sse_replay_size: DEFAULT_SSE_REPLAY_SIZE,
+        }
+    }
+}
+
+pub(super) fn parse_options(raw: &str) -> Result<HttpOptions, Box<dyn Error>> {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return Ok(HttpOptions::default());
+    }
+    let value: Value =
+        serde_json::from_str(trimmed).map_err(|e| format!("Некорректный JSON: {e}"))?;
+    let obj = value
+        .as_object()
+        .ok_or_else(|| "Ожидается JSON объект".to_owned())?;
+
+    let tls = match obj.get("tls") {
+        None | Some(Value::Null) => None,
+        Some(value) => Some(ServerTlsOptions::from_json(value)?),
+    };
+    let response_timeout = match option_u64(obj, "responseTimeoutMs")? {
+        None => Some(Duration::from_millis(DEFAULT_RESPONSE_TIMEOUT_MS)),
+        Some(0) => None,
+        Some(ms) => Some(Duration::from_millis(ms)),
+    };
+    let sse_keep_alive = match option_u64(obj, "sseKeepAliveMs")? {
+        None => Some(Duration::from_millis(DEFAULT_SSE_KEEP_ALIVE_MS)),
+        Some(0) => None,
+        Some(ms) => Some(Duration::from_millis(ms)),
+    };
+
+    let sse_replay_size = match option_u64(obj, "sseReplaySize")? {
+        None => DEFAULT_SSE_REPLAY_SIZE,
+        Some(size) => usize::try_from(size).map_err(|_| "Параметр sseReplaySize слишком велик")?,
+    };
+
+    Ok(HttpOptions {
+        tls,
+        response_timeout,
+        sse_keep_alive,
+        sse_replay_size,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_options_reads_response_timeout() {
+        let timeout = |raw: &str| parse_options(raw).unwrap().response_timeout;
+        assert_eq!(timeout(""), Some(Duration::from_secs(30)));
+        assert_eq!(
+            timeout(r#"{"responseTimeoutMs":120000}"#),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(timeout(r#"{"responseTimeoutMs":0}"#), None);
+        assert!(parse_options(r#"{"responseTimeoutMs":-1}"#).is_err());
+    }
+
+    #[test]
+    fn parse_options_reads_sse_settings() {
+        let keep_alive = |raw: &str| parse_options(raw).unwrap().sse_keep_alive;
+        assert_eq!(keep_alive(""), Some(Duration::from_secs(15)));
+        assert_eq!(
+            keep_alive(r#"{"sseKeepAliveMs":5000}"#),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(keep_alive(r#"{"sseKeepAliveMs":0}"#), None);
+
+        let replay_size = |raw: &str| parse_options(raw).unwrap().sse_replay_size;
+        assert_eq!(replay_size(""), 100);
+        assert_eq!(replay_size(r#"{"sseReplaySize":0}"#), 0);
+    }
+
+    #[test]
+    fn parse_options_requires_certificate_and_key() {
+        assert!(parse_options(r#"{"tls":{"cert":"server.pem"}}"#).is_err());
+        assert!(parse_options(r#"{"tls":true}"#).is_err());
+        assert!(parse_options("[]").is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::error::Error;
+
+use axum::http::Method;
+use percent_encoding::percent_decode_str;
+use serde_json::{Map, Value};
+
+/// Routes registered by 1C with `ДобавитьМаршрут`. While the table is empty every
+/// request is forwarded to 1C; once a route exists, only matching requests are.
+#[derive(Default)]
+pub(super) struct RouteTable {
+    routes: Vec<Route>,
+}
+
+struct Route {
+    /// `None` matches any method.
+    method: Option<Method>,
+    pattern: String,
+    segments: Vec<Segment>,
+}
+
+#[derive(PartialEq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    /// `{*name}`: the rest of the path, possibly empty.
+    CatchAll(String),
+}
+
+#[derive(Debug, PartialEq)]
+pub(super) enum RouteMatch {
+    /// No routes are registered.
+    Unrestricted,
+    Found {
+        route: String,
+        params: Map<String, Value>,
+    },
+    /// The path matches, the method does not; carries the allowed methods.
+    MethodNotAllowed(Vec<String>),
+    NotFound,
+}
+
+impl RouteTable {
+    pub(super) fn add(&mut self, method: &str, pattern: &str) -> Result<(), Box<dyn Error>> {
+        let route = Route::parse(method, pattern)?;
+        if self.position(&route.method, &route.pattern).is_some() {
+            return Err(format!("Маршрут {method} {pattern} уже добавлен").into());
+        }
+        self.routes.push(route);
+        Ok(())
+    }
+
+    pub(super) fn remove(&mut self, method: &str, pattern: &str) -> Result<(), Box<dyn Error>> {
+        let method = parse_method(method)?;
+        let index = self
+            .position(&method, pattern)
+            .ok_or_else(|| format!("Маршрут {pattern} не найден"))?;
+        self.routes.remove(index);
+        Ok(())
+    }
+
+    fn position(&self, method: &Option<Method>, pattern: &str) -> Option<usize> {
+        self.routes
+            .iter()
+            .position(|route| route.method == *method && route.pattern == pattern)
+    }
+
+    /// Routes are tried in the order they were added.
+    pub(super) fn find(&self, method: &Method, path: &str) -> RouteMatch {
+        if self.routes.is_empty() {
+            return RouteMatch::Unrestricted;
+        }
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            let Some(params) = route.match_path(path) else {
+                continue;
+            };
+            match &route.method {
+                Some(expected) if expected != method => {
+                    allowed.push(expected.as_str().to_owned());
+                }
+                _ => {
+                    return RouteMatch::Found {
+                        route: route.pattern.clone(),
+                        params,
+                    }
+                }
+            }
+        }
+        match allowed.is_empty() {
+            true => RouteMatch::NotFound,
+            false => {
+                allowed.sort();
+                allowed.dedup();
+                RouteMatch::MethodNotAllowed(allowed)
+            }
+        }
+    }
+}
+
+impl Route {
+    fn parse(method: &str, pattern: &str) -> Result<Self, Box<dyn Error>> {
+        let method = parse_method(method)?;
+        let Some(path) = pattern.strip_prefix('/') else {
+            return Err(format!("Шаблон маршрута должен начинаться с /: {pattern}").into());
+        };
+
+        let mut segments = Vec::new();
+        let mut names = Vec::new();
+        for segment in path.split('/') {
+            if matches!(segments.last(), Some(Segment::CatchAll(_))) {
+                return Err(format!("Параметр {{*...}} должен быть последним: {pattern}").into());
+            }
+            let segment = match segment
+                .strip_prefix('{')
+                .and_then(|name| name.strip_suffix('}'))
+            {
+                Some(name) => {
+                    let (name, catch_all) = match name.strip_prefix('*') {
+                        Some(name) => (name, true),
+                        None => (name, false),
+                    };
+                    let valid = !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_alphanumeric() || c == '_' || c == '-');
+                    if !valid || names.contains(&name) {
+                        return Err(format!("Некорректный параметр маршрута: {pattern}").into());
+                    }
+                    names.push(name);
+                    match catch_all {
+                        true => Segment::CatchAll(name.to_owned()),
+                        false => Segment::Param(name.to_owned()),
+                    }
+                }
+                None if segment.contains(['{', '}']) => {
+                    return Err(format!("Некорректный параметр маршрута: {pattern}").into())
+                }
+                None => Segment::Literal(segment.to_owned()),
+            };
+            segments.push(segment);
+        }
+
+        Ok(Self {
+            method,
+            pattern: pattern.to_owned(),
+            segments,
+        })
+    }
+
+    fn match_path(&self, path: &str) -> Option<Map<String, Value>> {
+        let mut parts = path.strip_prefix('/')?.split('/');
+        let mut params = Map::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(literal) => {
+                    if parts.next()? != literal {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    let part = parts.next().filter(|part| !part.is_empty())?;
+                    params.insert(name.clone(), decode(part).into());
+                }
+                Segment::CatchAll(name) => {
+                    let rest = parts.by_ref().collect::<Vec<_>>().join("/");
+                    params.insert(name.clone(), decode(&rest).into());
+                }
+            }
+        }
+        match parts.next() {
+            Some(_) => None,
+            None => Some(params),
+        }
+    }
+}
+
+/// An empty method or `*` registers the route for every method.
+fn parse_method(method: &str) -> Result<Option<Method>, Box<dyn Error>> {
+    let method = method.trim();
+    if method.is_empty() || method == "*" {
+        return Ok(None);
+    }
+    Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+        .map(Some)
+        .map_err(|_| format!("Некорректный HTTP метод: {method}").into())
+}
+
+fn decode(value: &str) -> String {
+    percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(routes: &[(&str, &str)]) -> RouteTable {
+        let mut table = RouteTable::default();
+        for (method, pattern) in routes {
+            table.add(method, pattern).unwrap();
+        }
+        table
+    }
+
+    fn found(route: &str, params: Value) -> RouteMatch {
+        RouteMatch::Found {
+            route: route.to_owned(),
+            params: params.as_object().unwrap().clone(),
+        }
+    }
+
+    #[test]
+    fn empty_table_forwards_everything() {
+        let table = RouteTable::default();
+        assert_eq!(
+            table.find(&Method::GET, "/wp-admin"),
+            RouteMatch::Unrestricted
+        );
+    }
+
+    #[test]
+    fn matches_literals_and_params() {
+        let table = table(&[
+            ("GET", "/orders"),
+            ("get", "/orders/{id}"),
+            ("POST", "/orders/{id}/items/{item}"),
+            ("*", "/files/{*path}"),
+        ]);
+
+        assert_eq!(
+            table.find(&Method::GET, "/orders"),
+            found("/orders", serde_json::json!({}))
+        );
+        assert_eq!(
+            table.find(&Method::GET, "/orders/%D0%97-1"),
+            found("/orders/{id}", serde_json::json!({ "id": "З-1" }))
+        );
+        assert_eq!(
+            table.find(&Method::POST, "/orders/7/items/3"),
+            found(
+                "/orders/{id}/items/{item}",
+                serde_json::json!({ "id": "7", "item": "3" })
+            )
+        );
+        assert_eq!(
+            table.find(&Method::PUT, "/files/2024/report.pdf"),
+            found(
+                "/files/{*path}",
+                serde_json::json!({ "path": "2024/report.pdf" })
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_paths_and_methods() {
+        let orders = table(&[("GET", "/orders/{id}"), ("DELETE", "/orders/{id}")]);
+
+        assert_eq!(orders.find(&Method::GET, "/wp-admin"), RouteMatch::NotFound);
+        assert_eq!(orders.find(&Method::GET, "/orders/"), RouteMatch::NotFound);
+        assert_eq!(
+            orders.find(&Method::GET, "/orders/1/x"),
+            RouteMatch::NotFound
+        );
+        assert_eq!(
+            orders.find(&Method::POST, "/orders/1"),
+            RouteMatch::MethodNotAllowed(vec!["DELETE".to_owned(), "GET".to_owned()])
+        );
+
+        let repeated = table(&[("GET", "/a/{x}"), ("POST", "/a/{x}"), ("GET", "/a/{y}")]);
+        assert_eq!(
+            repeated.find(&Method::PUT, "/a/1"),
+            RouteMatch::MethodNotAllowed(vec!["GET".to_owned(), "POST".to_owned()])
+        );
+    }
+
+    #[test]
+    fn validates_patterns() {
+        let mut table = RouteTable::default();
+        for pattern in [
+            "orders",
+            "/a/{}",
+            "/a/{id}/{id}",
+            "/a/{*rest}/b",
+            "/a/x{id}",
+        ] {
+            assert!(table.add("GET", pattern).is_err(), "{pattern}");
+        }
+        assert!(table.add("GE T", "/a").is_err());
+
+        table.add("GET", "/a").unwrap();
+        assert!(table.add("GET", "/a").is_err());
+        table.remove("get", "/a").unwrap();
+        assert!(table.remove("GET", "/a").is_err());
+    }
+}
@@ -1,11 +1,24 @@
+use super::auth::AuthPolicy;
+use super::cors::{self, CorsPolicy};
+use super::options::parse_options;
+use super::routes::{RouteMatch, RouteTable};
+use super::sse_handler::{self, OpenError, SseSessions};
+use super::tls_listener::TlsListener;
+use super::ws_handler::{self, WsSessions};
 use super::{mcp_handler, HttpAddIn};
+use crate::events::{connection_sink, notify, EventSink};
 use crate::parse_headers;
-use addin1c::{name, AddinResult, CString1C, Variant};
+use crate::tls::{load_certified_key, ServerCertResolver};
+use addin1c::{AddinResult, Variant};
 use axum::body::{to_bytes, Body};
+use axum::extract::ws::{Message, WebSocketUpgrade};
 use axum::extract::State;
-use axum::http::{HeaderMap, HeaderName, HeaderValue, Method, Request, Response, StatusCode};
+use axum::http::{HeaderMap, HeaderName, HeaderValue, Request, Response, StatusCode, Uri};
+use axum::middleware;
 use axum::routing::{get, post};
+use axum::serve::Listener;
 use axum::Router;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use bytes::Bytes;
 use futures_util::stream;
 use std::collections::HashMap;
@@ -13,26 +26,52 @@ use std::error::Error;
 use std::net::SocketAddr;
 use std::sync::{
     atomic::{AtomicU64, Ordering},
-    Arc,
+    Arc, RwLock,
 };
 use std::time::Duration;
 use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
 
-const HTTP_RESPONSE_TIMEOUT_SECS: u64 = 30;
+/// `ОтправитьHTTPОтвет` with this status only extends the deadline of the request.
+const STATUS_PROCESSING: u16 = 102;
+
+type ResponseMap = Arc<Mutex<HashMap<String, PendingResponse>>>;
+
+/// A request waiting for `ОтправитьHTTPОтвет`. `extend` moves its deadline;
+/// `None` extends it by the server-wide timeout.
+struct PendingResponse {
+    sender: oneshot::Sender<HttpResponse>,
+    extend: mpsc::UnboundedSender<Option<Duration>>,
+}
 
 pub(super) struct HttpServerState {
     pub(super) shutdown: oneshot::Sender<()>,
     pub(super) _join: tokio::task::JoinHandle<()>,
-    response_map: Arc<Mutex<HashMap<String, oneshot::Sender<HttpResponse>>>>,
+    response_map: ResponseMap,
+    /// Bodies of responses started with `НачатьHTTPОтвет`, by request id.
+    streams: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Bytes>>>>,
+    /// Present when the server was started with `tls`.
+    cert_resolver: Option<Arc<ServerCertResolver>>,
 }
 
 #[derive(Clone)]
 struct HttpAppState {
-    response_map: Arc<Mutex<HashMap<String, oneshot::Sender<HttpResponse>>>>,
+    response_map: ResponseMap,
     counter: Arc<AtomicU64>,
     connection: Option<&'static addin1c::Connection>,
-    sse_sessions: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<String>>>>,
+    /// Sink of the `HTTP*`, `SSE_*` and `WS_*` events; `None` without a 1C connection.
+    events: Option<EventSink>,
+    sse_sessions: SseSessions,
     sse_session_counter: Arc<AtomicU64>,
+    /// Interval of keep-alive comments in SSE streams; `None` disables them.
+    sse_keep_alive: Option<Duration>,
+    ws_sessions: WsSessions,
+    ws_session_counter: Arc<AtomicU64>,
+    scheme: &'static str,
+    response_timeout: Option<Duration>,
+    routes: Arc<RwLock<RouteTable>>,
+    auth: Arc<RwLock<Option<AuthPolicy>>>,
 }
 
 #[derive(Debug)]
@@ -42,34 +81,80 @@ struct HttpIncomingRequest {
     path: String,
     query: String,
     headers: HashMap<String, String>,
-    body: String,
+    body: Bytes,
+    /// Pattern of the matched route, `None` while no routes are registered.
+    route: Option<String>,
+    params: serde_json::Map<String, serde_json::Value>,
+    /// `null` while no authentication policy is set.
+    principal: serde_json::Value,
 }
 
 impl HttpIncomingRequest {
-    fn to_json(&self) -> String {
+    fn to_json(&self) -> serde_json::Value {
+        let content_type = self
+            .headers
+            .get("content-type")
+            .map(String::as_str)
+            .unwrap_or_default();
+        let text = match is_textual(content_type) {
+            true => std::str::from_utf8(&self.body).ok(),
+            false => None,
+        };
+        let (body, is_base64) = match text {
+            Some(text) => (text.to_owned(), false),
+            None => (BASE64.encode(&self.body), true),
+        };
         serde_json::json!({
             "id": self.id,
             "method": self.method,
             "path": self.path,
             "query": self.query,
             "headers": self.headers,
-            "body": self.body,
+            "body": body,
+            "isBase64": is_base64,
+            "route": self.route,
+            "params": self.params,
+            "principal": self.principal,
         })
-        .to_string()
     }
 }
 
+/// Bodies of these types reach 1C as text when they are valid UTF-8; a request
+/// without `Content-Type` is treated as text too.
+fn is_textual(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.is_empty()
+        || mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/xml"
+                | "application/javascript"
+                | "application/x-www-form-urlencoded"
+        )
+}
+
 #[derive(Debug)]
 struct HttpResponse {
     status: u16,
     headers: HashMap<String, String>,
-    body: String,
+    body: Body,
+    /// `Content-Type` used when 1C did not set one.
+    default_content_type: &'static str,
 }
 
 impl HttpAddIn {
     pub(super) fn http_start(
         &mut self,
         address: &mut Variant,
+        options: &mut Variant,
         return_value: &mut Variant,
     ) -> AddinResult {
         if self.http_server.is_some() {
@@ -80,14 +165,34 @@ impl HttpAddIn {
         let addr: SocketAddr = address
             .parse()
             .map_err(|err| format!("Некорректный адрес: {err}"))?;
+        let options = match options.get_empty() {
+            Ok(()) => String::new(),
+            Err(_) => options.get_string()?,
+        };
+        let options = parse_options(&options)?;
+        let cert_resolver = options
+            .tls
+            .map(|tls| Arc::new(ServerCertResolver::new(tls.certified_key)));
+        let acceptor = match &cert_resolver {
+            Some(resolver) => Some(TlsAcceptor::from(Arc::new(resolver.server_config()?))),
+            None => None,
+        };
         let (shutdown_tx, shutdown_rx) = oneshot::channel();
         let response_map = Arc::new(Mutex::new(HashMap::new()));
         let state = HttpAppState {
             response_map: response_map.clone(),
             counter: self.http_request_counter.clone(),
             connection: self.connection,
+            events: connection_sink(self.connection),
             sse_sessions: self.sse_sessions.clone(),
             sse_session_counter: self.sse_session_counter.clone(),
+            sse_keep_alive: options.sse_keep_alive,
+            ws_sessions: self.ws_sessions.clone(),
+            ws_session_counter: self.ws_session_counter.clone(),
+            scheme: if acceptor.is_some() { "https" } else { "http" },
+            response_timeout: options.response_timeout,
+            routes: self.routes.clone(),
+            auth: self.auth.clone(),
         };
 
         let listener = self
@@ -95,43 +200,137 @@ impl HttpAddIn {
             .block_on(async { tokio::net::TcpListener::bind(addr).await })
             .map_err(|err| format!("Не удалось запустить HTTP сервер: {err}"))?;
 
-        let join = self.runtime.spawn(async move {
-            let app = Router::new()
-                .route("/sse", get(handle_sse_request))
-                .route("/message", post(handle_mcp_route))
-                .route("/", get(handle_root))
-                .fallback(handle_http_request)
-                .with_state(state);
+        let app = router(state, self.cors.clone());
 
-            let server = axum::serve(listener, app).with_graceful_shutdown(async {
-                let _ = shutdown_rx.await;
-            });
+        let join = match acceptor {
+            Some(acceptor) => {
+                let listener = self
+                    .runtime
+                    .block_on(async { TlsListener::new(listener, acceptor) })
+                    .map_err(|err| format!("Не удалось запустить HTTP сервер: {err}"))?;
+                self.runtime.spawn(serve(listener, app, shutdown_rx))
+            }
+            None => self.runtime.spawn(serve(listener, app, shutdown_rx)),
+        };
 
-            let _ = server.await;
+        self.runtime.clone().block_on(async {
+            let mut hub = self.sse_sessions.lock().await;
+            hub.set_replay_size(options.sse_replay_size);
         });
-
         self.http_server = Some(HttpServerState {
             shutdown: shutdown_tx,
             _join: join,
             response_map,
+            streams: Arc::new(Mutex::new(HashMap::new())),
+            cert_resolver,
         });
 
         return_value.set_bool(true);
         Ok(())
     }
 
+    pub(super) fn http_reload_certificate(
+        &mut self,
+        cert: &mut Variant,
+        key: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let cert = cert.get_string()?;
+        let key = key.get_string()?;
+        let Some(server) = self.http_server.as_ref() else {
+            return Err("HTTP сервер не запущен".to_owned().into());
+        };
+        let Some(resolver) = server.cert_resolver.as_ref() else {
+            return Err("HTTP сервер запущен без TLS".to_owned().into());
+        };
+
+        resolver.set(load_certified_key(&cert, &key)?);
+        return_value.set_bool(true);
+        Ok(())
+    }
+
+    pub(super) fn route_add(
+        &mut self,
+        method: &mut Variant,
+        pattern: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let method = method.get_string()?;
+        let pattern = pattern.get_string()?;
+        self.routes.write().unwrap().add(&method, &pattern)?;
+        return_value.set_bool(true);
+        Ok(())
+    }
+
+    pub(super) fn route_remove(
+        &mut self,
+        method: &mut Variant,
+        pattern: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let method = method.get_string()?;
+        let pattern = pattern.get_string()?;
+        self.routes.write().unwrap().remove(&method, &pattern)?;
+        return_value.set_bool(true);
+        Ok(())
+    }
+
+    pub(super) fn set_auth(
+        &mut self,
+        options: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let options = options.get_string()?;
+        let policy = match options.trim() {
+            "" => None,
+            options => {
+                let value: serde_json::Value =
+                    serde_json::from_str(options).map_err(|e| format!("Некорректный JSON: {e}"))?;
+                Some(AuthPolicy::from_json(&value)?)
+            }
+        };
+        *self.auth.write().unwrap() = policy;
+        return_value.set_bool(true);
+        Ok(())
+    }
+
+    pub(super) fn set_cors(
+        &mut self,
+        options: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let options = options.get_string()?;
+        let policy = match options.trim() {
+            "" => CorsPolicy::default(),
+            options => {
+                let value: serde_json::Value =
+                    serde_json::from_str(options).map_err(|e| format!("Некорректный JSON: {e}"))?;
+                CorsPolicy::from_json(&value)?
+            }
+        };
+        *self.cors.write().unwrap() = policy;
+        return_value.set_bool(true);
+        Ok(())
+    }
+
     pub(super) fn http_stop(&mut self, return_value: &mut Variant) -> AddinResult {
         let Some(server) = self.http_server.take() else {
             return Err("HTTP сервер не запущен".to_owned().into());
         };
 
         let response_map = server.response_map.clone();
+        let streams = server.streams.clone();
         let sse_sessions = self.sse_sessions.clone();
+        let ws_sessions = self.ws_sessions.clone();
         self.runtime.clone().block_on(async {
             let mut map = response_map.lock().await;
             map.clear();
-            let mut sse_map = sse_sessions.lock().await;
-            sse_map.clear();
+            let mut stream_map = streams.lock().await;
+            stream_map.clear();
+            let mut sse_hub = sse_sessions.lock().await;
+            sse_hub.clear();
+            let mut ws_map = ws_sessions.lock().await;
+            ws_map.clear();
         });
 
         let _ = server.shutdown.send(());
@@ -148,31 +347,215 @@ impl HttpAddIn {
         return_value: &mut Variant,
     ) -> AddinResult {
         let request_id = request_id.get_string()?;
-        let status_code = status_code.get_i32()?;
-        if !(100..=599).contains(&status_code) {
-            return Err("Некорректный HTTP статус".to_owned().into());
+        let status_code = response_status(status_code)?;
+        if status_code == STATUS_PROCESSING {
+            return self.extend_deadline(request_id.as_str(), None, return_value);
         }
         let json_headers = json_headers.get_string()?;
         let body = body.get_string()?;
 
         let headers = parse_headers(json_headers)?;
         let response = HttpResponse {
-            status: status_code as u16,
+            status: status_code,
             headers,
-            body,
+            body: Body::from(body),
+            default_content_type: "application/json; charset=utf-8",
+        };
+        self.send_response(request_id.as_str(), response, return_value)
+    }
+
+    pub(super) fn http_send_binary_response(
+        &mut self,
+        request_id: &mut Variant,
+        status_code: &mut Variant,
+        json_headers: &mut Variant,
+        data: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let request_id = request_id.get_string()?;
+        let status_code = response_status(status_code)?;
+        if status_code == STATUS_PROCESSING {
+            return self.extend_deadline(request_id.as_str(), None, return_value);
+        }
+        let json_headers = json_headers.get_string()?;
+        let data = data
+            .get_blob()
+            .map_err(|_| "Ожидаются двоичные данные".to_owned())?
+            .to_vec();
+
+        let headers = parse_headers(json_headers)?;
+        let response = HttpResponse {
+            status: status_code,
+            headers,
+            body: Body::from(data),
+            default_content_type: "application/octet-stream",
         };
+        self.send_response(request_id.as_str(), response, return_value)
+    }
+
+    fn send_response(
+        &mut self,
+        request_id: &str,
+        response: HttpResponse,
+        return_value: &mut Variant,
+    ) -> AddinResult {
         let Some(server) = self.http_server.as_ref() else {
             return Err("HTTP сервер не запущен".to_owned().into());
         };
 
         self.runtime.clone().block_on(async {
             let mut map = server.response_map.lock().await;
+            let pending = map
+                .remove(request_id)
+                .ok_or_else(|| "Не найден ожидающий ответ запрос".to_owned())?;
+            pending
+                .sender
+                .send(response)
+                .map_err(|_| -> Box<dyn Error> {
+                    "Не удалось отправить ответ".to_owned().into()
+                })?;
+            return_value.set_bool(true);
+            Ok(())
+        })
+    }
+
+    pub(super) fn http_begin_response(
+        &mut self,
+        request_id: &mut Variant,
+        status_code: &mut Variant,
+        json_headers: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let request_id = request_id.get_string()?;
+        let status_code = response_status(status_code)?;
+        if status_code < 200 {
+            return Err(
+                "Потоковый ответ должен начинаться с финального HTTP статуса"
+                    .to_owned()
+                    .into(),
+            );
+        }
+        let json_headers = json_headers.get_string()?;
+        let headers = parse_headers(json_headers)?;
+        let Some(streams) = self
+            .http_server
+            .as_ref()
+            .map(|server| server.streams.clone())
+        else {
+            return Err("HTTP сервер не запущен".to_owned().into());
+        };
+
+        let (tx, body) = channel_body();
+        let response = HttpResponse {
+            status: status_code,
+            headers,
+            body,
+            default_content_type: "application/octet-stream",
+        };
+
+        // The stream is registered first: once the response is sent, 1C may call
+        // `ОтправитьHTTPЧасть` from another thread before this method returns.
+        self.runtime.clone().block_on(async {
+            streams.lock().await.insert(request_id.clone(), tx);
+        });
+        let result = self.send_response(request_id.as_str(), response, return_value);
+        if result.is_err() {
+            self.runtime.clone().block_on(async {
+                streams.lock().await.remove(request_id.as_str());
+            });
+        }
+        result
+    }
+
+    pub(super) fn http_send_chunk(
+        &mut self,
+        request_id: &mut Variant,
+        data: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let request_id = request_id.get_string()?;
+        let chunk = match data.get_blob() {
+            Ok(data) => Bytes::copy_from_slice(data),
+            Err(_) => Bytes::from(data.get_string()?),
+        };
+        let Some(server) = self.http_server.as_ref() else {
+            return Err("HTTP сервер не запущен".to_owned().into());
+        };
+
+        self.runtime.clone().block_on(async {
+            let mut map = server.streams.lock().await;
             let sender = map
-                .remove(request_id.as_str())
+                .get(request_id.as_str())
+                .ok_or_else(|| "Не найден начатый ответ".to_owned())?;
+            if sender.send(chunk).is_err() {
+                map.remove(request_id.as_str());
+                return Err("Клиент закрыл соединение".to_owned().into());
+            }
+            return_value.set_bool(true);
+            Ok(())
+        })
+    }
+
+    pub(super) fn http_end_response(
+        &mut self,
+        request_id: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let request_id = request_id.get_string()?;
+        let Some(server) = self.http_server.as_ref() else {
+            return Err("HTTP сервер не запущен".to_owned().into());
+        };
+
+        self.runtime.clone().block_on(async {
+            let mut map = server.streams.lock().await;
+            map.remove(request_id.as_str())
+                .ok_or_else(|| "Не найден начатый ответ".to_owned())?;
+            return_value.set_bool(true);
+            Ok(())
+        })
+    }
+
+    pub(super) fn http_extend_deadline(
+        &mut self,
+        request_id: &mut Variant,
+        timeout: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let request_id = request_id.get_string()?;
+        let timeout = match timeout.get_empty() {
+            Ok(()) => None,
+            Err(_) => {
+                let timeout = timeout.get_i32()?;
+                if timeout <= 0 {
+                    return Err("Таймаут должен быть больше 0".to_owned().into());
+                }
+                Some(Duration::from_millis(timeout as u64))
+            }
+        };
+        self.extend_deadline(request_id.as_str(), timeout, return_value)
+    }
+
+    fn extend_deadline(
+        &mut self,
+        request_id: &str,
+        timeout: Option<Duration>,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let Some(server) = self.http_server.as_ref() else {
+            return Err("HTTP сервер не запущен".to_owned().into());
+        };
+
+        self.runtime.clone().block_on(async {
+            let map = server.response_map.lock().await;
+            let pending = map
+                .get(request_id)
                 .ok_or_else(|| "Не найден ожидающий ответ запрос".to_owned())?;
-            sender.send(response).map_err(|_| -> Box<dyn Error> {
-                "Не удалось отправить ответ".to_owned().into()
-            })?;
+            pending
+                .extend
+                .send(timeout)
+                .map_err(|_| -> Box<dyn Error> {
+                    "Не удалось продлить ожидание ответа".to_owned().into()
+                })?;
             return_value.set_bool(true);
             Ok(())
         })
@@ -182,20 +565,89 @@ impl HttpAddIn {
         &mut self,
         session_id: &mut Variant,
         data: &mut Variant,
+        event: &mut Variant,
+        event_id: &mut Variant,
+        retry: &mut Variant,
         return_value: &mut Variant,
     ) -> AddinResult {
         let session_id = session_id.get_string()?;
-        let data = data.get_string()?;
+        let (event_id, frame) = sse_event(data, event, event_id, retry)?;
         let sse_sessions = self.sse_sessions.clone();
         self.runtime.clone().block_on(async {
-            let map = sse_sessions.lock().await;
-            let sender = map
-                .get(session_id.as_str())
-                .ok_or_else(|| "SSE сессия не найдена".to_owned())?;
-            let payload = sse_format_message_event(data.as_str());
-            sender.send(payload).map_err(|_| -> Box<dyn Error> {
-                "Не удалось отправить SSE событие".to_owned().into()
-            })?;
+            let mut hub = sse_sessions.lock().await;
+            let delivered = hub.send(session_id.as_str(), event_id.as_deref(), frame)?;
+            return_value.set_bool(delivered);
+            Ok(())
+        })
+    }
+
+    pub(super) fn sse_send_to_channel(
+        &mut self,
+        channel: &mut Variant,
+        data: &mut Variant,
+        event: &mut Variant,
+        event_id: &mut Variant,
+        retry: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let channel = channel.get_string()?;
+        let (event_id, frame) = sse_event(data, event, event_id, retry)?;
+        let sse_sessions = self.sse_sessions.clone();
+        self.runtime.clone().block_on(async {
+            let mut hub = sse_sessions.lock().await;
+            let delivered = hub.send_to_channel(channel.as_str(), event_id.as_deref(), frame);
+            return_value.set_i32(delivered.try_into().unwrap_or(i32::MAX));
+            Ok(())
+        })
+    }
+
+    pub(super) fn sse_broadcast(
+        &mut self,
+        data: &mut Variant,
+        event: &mut Variant,
+        event_id: &mut Variant,
+        retry: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let (event_id, frame) = sse_event(data, event, event_id, retry)?;
+        let sse_sessions = self.sse_sessions.clone();
+        self.runtime.clone().block_on(async {
+            let mut hub = sse_sessions.lock().await;
+            let delivered = hub.broadcast(event_id.as_deref(), frame);
+            return_value.set_i32(delivered.try_into().unwrap_or(i32::MAX));
+            Ok(())
+        })
+    }
+
+    pub(super) fn sse_join(
+        &mut self,
+        session_id: &mut Variant,
+        channel: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let session_id = session_id.get_string()?;
+        let channel = channel_name(channel)?;
+        let sse_sessions = self.sse_sessions.clone();
+        self.runtime.clone().block_on(async {
+            let mut hub = sse_sessions.lock().await;
+            hub.join(session_id.as_str(), channel.as_str())?;
+            return_value.set_bool(true);
+            Ok(())
+        })
+    }
+
+    pub(super) fn sse_leave(
+        &mut self,
+        session_id: &mut Variant,
+        channel: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let session_id = session_id.get_string()?;
+        let channel = channel_name(channel)?;
+        let sse_sessions = self.sse_sessions.clone();
+        self.runtime.clone().block_on(async {
+            let mut hub = sse_sessions.lock().await;
+            hub.leave(session_id.as_str(), channel.as_str());
             return_value.set_bool(true);
             Ok(())
         })
@@ -209,50 +661,233 @@ impl HttpAddIn {
         let session_id = session_id.get_string()?;
         let sse_sessions = self.sse_sessions.clone();
         self.runtime.clone().block_on(async {
-            let mut map = sse_sessions.lock().await;
-            map.remove(session_id.as_str());
+            let mut hub = sse_sessions.lock().await;
+            hub.close(session_id.as_str());
+            return_value.set_bool(true);
+            Ok(())
+        })
+    }
+
+    pub(super) fn ws_send(
+        &mut self,
+        session_id: &mut Variant,
+        message: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let session_id = session_id.get_string()?;
+        let message = message.get_string()?;
+        let ws_sessions = self.ws_sessions.clone();
+        self.runtime.clone().block_on(async {
+            let map = ws_sessions.lock().await;
+            let sender = map
+                .get(session_id.as_str())
+                .ok_or_else(|| "WebSocket сессия не найдена".to_owned())?;
+            sender
+                .send(Message::Text(message.into()))
+                .map_err(|_| -> Box<dyn Error> {
+                    "Не удалось отправить WebSocket сообщение".to_owned().into()
+                })?;
+            return_value.set_bool(true);
+            Ok(())
+        })
+    }
+
+    pub(super) fn ws_close(
+        &mut self,
+        session_id: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        let session_id = session_id.get_string()?;
+        let ws_sessions = self.ws_sessions.clone();
+        self.runtime.clone().block_on(async {
+            let mut map = ws_sessions.lock().await;
+            if let Some(sender) = map.remove(session_id.as_str()) {
+                let _ = sender.send(ws_handler::close_message(1000, ""));
+            }
             return_value.set_bool(true);
             Ok(())
         })
     }
 }
 
+fn router(state: HttpAppState, cors: Arc<RwLock<CorsPolicy>>) -> Router {
+    Router::new()
+        .route("/sse", get(handle_sse_request))
+        .route("/ws", get(handle_ws_request))
+        .route("/message", post(handle_mcp_route))
+        .route("/", get(handle_root))
+        .fallback(handle_http_request)
+        .layer(middleware::from_fn_with_state(cors, cors::apply))
+        .with_state(state)
+}
+
+async fn serve<L>(listener: L, app: Router, shutdown: oneshot::Receiver<()>)
+where
+    L: Listener,
+    L::Addr: std::fmt::Debug,
+{
+    let server = axum::serve(listener, app).with_graceful_shutdown(async {
+        let _ = shutdown.await;
+    });
+
+    let _ = server.await;
+}
+
+/// A body that streams whatever is sent to the channel and ends once the sender is dropped.
+fn channel_body() -> (mpsc::UnboundedSender<Bytes>, Body) {
+    let (tx, rx) = mpsc::unbounded_channel::<Bytes>();
+    let stream = stream::unfold(rx, |mut rx| async {
+        rx.recv()
+            .await
+            .map(|chunk| (Ok::<Bytes, std::io::Error>(chunk), rx))
+    });
+    (tx, Body::from_stream(stream))
+}
+
+/// Checks the request against the policy set by `УстановитьАвторизацию`. Returns the
+/// principal for 1C (`null` without a policy) or the 401 response.
+fn authenticate(
+    state: &HttpAppState,
+    headers: &HeaderMap,
+) -> Result<serde_json::Value, Box<Response<Body>>> {
+    let auth = state.auth.read().unwrap();
+    let Some(policy) = auth.as_ref() else {
+        return Ok(serde_json::Value::Null);
+    };
+    policy.authenticate(headers).ok_or_else(|| {
+        let response = Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .header("WWW-Authenticate", policy.challenge())
+            .body(Body::from("Unauthorized"))
+            .unwrap();
+        Box::new(response)
+    })
+}
+
+/// Formats the event of `ОтправитьSSE` and friends; returns its id for the replay buffer.
+fn sse_event(
+    data: &mut Variant,
+    event: &mut Variant,
+    event_id: &mut Variant,
+    retry: &mut Variant,
+) -> Result<(Option<String>, String), Box<dyn Error>> {
+    let data = data.get_string()?;
+    let event = optional_string(event)?;
+    let event_id = optional_string(event_id)?;
+    if [&event, &event_id]
+        .into_iter()
+        .flatten()
+        .any(|value| value.contains(['\r', '\n']))
+    {
+        return Err(
+            "Имя и идентификатор SSE события не должны содержать перевод строки"
+                .to_owned()
+                .into(),
+        );
+    }
+    let retry = match retry.get_empty() {
+        Ok(()) => None,
+        Err(_) => Some(
+            u32::try_from(retry.get_i32()?)
+                .map_err(|_| "Интервал переподключения не может быть отрицательным")?,
+        ),
+    };
+
+    let frame = sse_handler::format_event(
+        event.as_deref().unwrap_or("message"),
+        data.as_str(),
+        event_id.as_deref(),
+        retry,
+    );
+    Ok((event_id, frame))
+}
+
+fn channel_name(channel: &mut Variant) -> Result<String, Box<dyn Error>> {
+    let channel = channel.get_string()?;
+    match channel.trim().is_empty() {
+        true => Err("Не указан канал SSE".to_owned().into()),
+        false => Ok(channel),
+    }
+}
+
+/// An omitted or empty optional parameter is `None`.
+fn optional_string(value: &mut Variant) -> Result<Option<String>, Box<dyn Error>> {
+    match value.get_empty() {
+        Ok(()) => Ok(None),
+        Err(_) => Ok(Some(value.get_string()?).filter(|value| !value.is_empty())),
+    }
+}
+
+fn response_status(status_code: &mut Variant) -> Result<u16, Box<dyn Error>> {
+    check_status(status_code.get_i32()?)
+}
+
+/// A final status, or 102 that only extends the wait: hyper replaces any other
+/// 1xx sent as the final response with a 500.
+fn check_status(status_code: i32) -> Result<u16, Box<dyn Error>> {
+    if status_code != i32::from(STATUS_PROCESSING) && !(200..=599).contains(&status_code) {
+        return Err("Некорректный HTTP статус".to_owned().into());
+    }
+    Ok(status_code as u16)
+}
+
 async fn handle_root() -> Response<Body> {
-    let mut response = Response::builder()
+    Response::builder()
         .status(StatusCode::OK)
         .body(Body::from("MCP server"))
-        .unwrap();
-    add_cors_headers(response.headers_mut());
-    response
+        .unwrap()
 }
 
 async fn handle_mcp_route(State(state): State<HttpAppState>, req: Request<Body>) -> Response<Body> {
-    let mut response = match mcp_handler::handle_mcp_message(req, state.connection).await {
+    if let Err(response) = authenticate(&state, req.headers()) {
+        return *response;
+    }
+    match mcp_handler::handle_mcp_message(req, state.connection).await {
         Ok(response) => response,
         Err(err) => match err {},
-    };
-    add_cors_headers(response.headers_mut());
-    response
+    }
 }
 
 async fn handle_http_request(
     State(state): State<HttpAppState>,
     req: Request<Body>,
 ) -> Response<Body> {
-    if req.method() == Method::OPTIONS {
-        return cors_preflight_response();
-    }
+    let principal = match authenticate(&state, req.headers()) {
+        Ok(principal) => principal,
+        Err(response) => return *response,
+    };
+
+    let found = state
+        .routes
+        .read()
+        .unwrap()
+        .find(req.method(), req.uri().path());
+    let (route, params) = match found {
+        RouteMatch::Unrestricted => (None, serde_json::Map::new()),
+        RouteMatch::Found { route, params } => (Some(route), params),
+        RouteMatch::MethodNotAllowed(allowed) => {
+            return Response::builder()
+                .status(StatusCode::METHOD_NOT_ALLOWED)
+                .header("Allow", allowed.join(", "))
+                .body(Body::from("Method not allowed"))
+                .unwrap();
+        }
+        RouteMatch::NotFound => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from("Not found"))
+                .unwrap();
+        }
+    };
 
     let (parts, body) = req.into_parts();
     let body_bytes = match to_bytes(body, 16 * 1024 * 1024).await {
         Ok(bytes) => bytes,
         Err(_) => {
-            let mut response = Response::builder()
+            return Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(Body::from("Failed to read request body"))
                 .unwrap();
-            add_cors_headers(response.headers_mut());
-            return response;
         }
     };
 
@@ -272,48 +907,58 @@ async fn handle_http_request(
         path: parts.uri.path().to_owned(),
         query: parts.uri.query().unwrap_or("").to_owned(),
         headers,
-        body: String::from_utf8_lossy(&body_bytes).to_string(),
+        body: body_bytes,
+        route,
+        params,
+        principal,
     };
 
     let (response_tx, response_rx) = oneshot::channel();
+    let (extend_tx, extend_rx) = mpsc::unbounded_channel();
     {
         let mut map = state.response_map.lock().await;
-        map.insert(id.clone(), response_tx);
+        let pending = PendingResponse {
+            sender: response_tx,
+            extend: extend_tx,
+        };
+        map.insert(id.clone(), pending);
     }
 
-    let Some(connection) = state.connection else {
+    let Some(events) = state.events.as_ref() else {
         let mut map = state.response_map.lock().await;
         map.remove(&id);
-        let mut response = Response::builder()
+        return Response::builder()
             .status(StatusCode::SERVICE_UNAVAILABLE)
             .body(Body::from("Event connection is unavailable"))
             .unwrap();
-        add_cors_headers(response.headers_mut());
-        return response;
     };
 
-    let data = CString1C::from(request.to_json().as_str());
-    if !connection.external_event(name!("WebTransport"), name!("HTTP"), data) {
+    if !events("HTTP", request.to_json()) {
         let mut map = state.response_map.lock().await;
         map.remove(&id);
-        let mut response = Response::builder()
+        return Response::builder()
             .status(StatusCode::SERVICE_UNAVAILABLE)
             .body(Body::from("Event queue is full"))
             .unwrap();
-        add_cors_headers(response.headers_mut());
-        return response;
     }
 
-    match tokio::time::timeout(Duration::from_secs(HTTP_RESPONSE_TIMEOUT_SECS), response_rx).await {
+    let mut pending = PendingGuard {
+        id: Some(id.clone()),
+        response_map: state.response_map.clone(),
+        events: state.events.clone(),
+    };
+    let response = wait_for_response(response_rx, extend_rx, state.response_timeout).await;
+    pending.disarm();
+    match response {
         Ok(Ok(response)) => {
             let mut builder = Response::builder()
                 .status(StatusCode::from_u16(response.status).unwrap_or(StatusCode::OK));
             if response
                 .headers
                 .keys()
-                .all(|key| key.to_ascii_lowercase() != "content-type")
+                .all(|key| !key.eq_ignore_ascii_case("content-type"))
             {
-                builder = builder.header("Content-Type", "application/json; charset=utf-8");
+                builder = builder.header("Content-Type", response.default_content_type);
             }
             for (key, value) in response.headers {
                 let name = HeaderName::from_bytes(key.as_bytes());
@@ -322,27 +967,81 @@ async fn handle_http_request(
                     builder = builder.header(name, value);
                 }
             }
-            let mut resp = builder.body(Body::from(response.body)).unwrap();
-            add_cors_headers(resp.headers_mut());
-            resp
-        }
-        Ok(Err(_)) => {
-            let mut response = Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::from("Response channel closed"))
-                .unwrap();
-            add_cors_headers(response.headers_mut());
-            response
+            builder.body(response.body).unwrap()
         }
+        Ok(Err(_)) => Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .body(Body::from("Response channel closed"))
+            .unwrap(),
         Err(_) => {
-            let mut map = state.response_map.lock().await;
-            map.remove(&id);
-            let mut response = Response::builder()
+            cancel_pending(&state.response_map, state.events.as_ref(), id, "timeout").await;
+            Response::builder()
                 .status(StatusCode::GATEWAY_TIMEOUT)
                 .body(Body::from("Handler timeout"))
-                .unwrap();
-            add_cors_headers(response.headers_mut());
-            response
+                .unwrap()
+        }
+    }
+}
+
+/// Cancels the pending request when the client goes away before 1C answers:
+/// axum drops the handler future, and with it this guard.
+struct PendingGuard {
+    id: Option<String>,
+    response_map: ResponseMap,
+    events: Option<EventSink>,
+}
+
+impl PendingGuard {
+    fn disarm(&mut self) {
+        self.id = None;
+    }
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let Some(id) = self.id.take() else {
+            return;
+        };
+        let response_map = self.response_map.clone();
+        let events = self.events.take();
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            handle.spawn(async move {
+                cancel_pending(&response_map, events.as_ref(), id, "disconnect").await;
+            });
+        }
+    }
+}
+
+/// Forgets the request and raises `HTTP_CANCELLED`, unless 1C has already answered it.
+async fn cancel_pending(
+    response_map: &ResponseMap,
+    events: Option<&EventSink>,
+    id: String,
+    reason: &str,
+) {
+    if response_map.lock().await.remove(&id).is_none() {
+        return;
+    }
+    let payload = serde_json::json!({ "id": id, "reason": reason });
+    notify(events, "HTTP_CANCELLED", payload, false).await;
+}
+
+/// Waits for the answer of 1C until the deadline, which `extend` may move forward.
+/// `Err` means the deadline has passed.
+async fn wait_for_response(
+    mut response: oneshot::Receiver<HttpResponse>,
+    mut extend: mpsc::UnboundedReceiver<Option<Duration>>,
+    timeout: Option<Duration>,
+) -> Result<Result<HttpResponse, oneshot::error::RecvError>, ()> {
+    let mut deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        tokio::select! {
+            response = &mut response => return Ok(response),
+            Some(extension) = extend.recv() => {
+                deadline = extension.or(timeout).map(|timeout| Instant::now() + timeout);
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                if deadline.is_some() => return Err(()),
         }
     }
 }
@@ -351,6 +1050,10 @@ async fn handle_sse_request(
     State(state): State<HttpAppState>,
     req: Request<Body>,
 ) -> Response<Body> {
+    let principal = match authenticate(&state, req.headers()) {
+        Ok(principal) => principal,
+        Err(response) => return *response,
+    };
     let session_id = match get_query_param(req.uri().query().unwrap_or(""), "sessionId") {
         Some(value) => value,
         None => state
@@ -358,96 +1061,118 @@ async fn handle_sse_request(
             .fetch_add(1, Ordering::Relaxed)
             .to_string(),
     };
+    let last_event_id = req
+        .headers()
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned);
     let (tx, rx) = mpsc::unbounded_channel::<String>();
 
-    {
-        let mut map = state.sse_sessions.lock().await;
-        map.insert(session_id.clone(), tx.clone());
-    }
-
     let host = req
         .headers()
         .get("host")
         .and_then(|h| h.to_str().ok())
         .unwrap_or("127.0.0.1");
-    let endpoint = format!("http://{host}/message?sessionId={session_id}");
-    let initial = sse_format_event("endpoint", endpoint.as_str());
+    let endpoint = format!("{}://{host}/message?sessionId={session_id}", state.scheme);
+    let initial = sse_handler::format_event("endpoint", endpoint.as_str(), None, None);
     let _ = tx.send(initial);
 
-    if let Some(connection) = state.connection {
-        let data = CString1C::from(
-            serde_json::json!({
-                "id": session_id,
-                "path": "/sse",
-                "headers": {},
-            })
-            .to_string()
-            .as_str(),
-        );
-        let _ = connection.external_event(name!("WebTransport"), name!("SSE_OPEN"), data);
+    let channels = get_query_param(req.uri().query().unwrap_or(""), "channel")
+        .map(|value| {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|channel| !channel.is_empty())
+                .map(str::to_owned)
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    {
+        let mut hub = state.sse_sessions.lock().await;
+        let opened = hub.open(&session_id, &principal, tx, last_event_id.as_deref());
+        let rejected = match opened {
+            Ok(()) => None,
+            Err(OpenError::Connected) => {
+                Some((StatusCode::CONFLICT, "SSE session is already connected"))
+            }
+            Err(OpenError::Forbidden) => Some((
+                StatusCode::FORBIDDEN,
+                "Forbidden: SSE session belongs to another client",
+            )),
+        };
+        if let Some((status, message)) = rejected {
+            return Response::builder()
+                .status(status)
+                .body(Body::from(message))
+                .unwrap();
+        }
+        for channel in &channels {
+            let _ = hub.join(&session_id, channel);
+        }
     }
 
-    let stream = stream::unfold(rx, |mut rx| async {
-        match rx.recv().await {
-            Some(item) => Some((Ok::<Bytes, std::io::Error>(Bytes::from(item)), rx)),
-            None => None,
-        }
+    let open = serde_json::json!({
+        "id": session_id,
+        "path": "/sse",
+        "headers": {},
+        "principal": principal,
+        "lastEventId": last_event_id,
+        "channels": channels,
     });
+    notify(state.events.as_ref(), "SSE_OPEN", open, false).await;
 
-    let mut response = Response::builder()
+    let body = sse_handler::event_stream(
+        session_id,
+        rx,
+        state.sse_sessions.clone(),
+        state.sse_keep_alive,
+        state.events.clone(),
+    );
+
+    Response::builder()
         .status(StatusCode::OK)
         .header("Content-Type", "text/event-stream")
         .header("Cache-Control", "no-cache")
         .header("Connection", "keep-alive")
         .header("X-Accel-Buffering", "no")
-        .body(Body::from_stream(stream))
-        .unwrap();
-    add_cors_headers(response.headers_mut());
-    response
-}
-
-fn sse_format_event(event: &str, data: &str) -> String {
-    let mut out = String::new();
-    out.push_str("event: ");
-    out.push_str(event);
-    out.push('\n');
-    if data.is_empty() {
-        out.push_str("data:\n");
-    } else {
-        for line in data.lines() {
-            out.push_str("data: ");
-            out.push_str(line);
-            out.push('\n');
-        }
-    }
-    out.push('\n');
-    out
+        .body(body)
+        .unwrap()
 }
 
-fn sse_format_message_event(data: &str) -> String {
-    sse_format_event("message", data)
-}
-
-fn cors_preflight_response() -> Response<Body> {
-    let mut response = Response::builder()
-        .status(StatusCode::NO_CONTENT)
-        .body(Body::empty())
-        .unwrap();
-    add_cors_headers(response.headers_mut());
-    response
-}
+async fn handle_ws_request(
+    State(state): State<HttpAppState>,
+    upgrade: WebSocketUpgrade,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let principal = match authenticate(&state, &headers) {
+        Ok(principal) => principal,
+        Err(response) => return *response,
+    };
+    let query = uri.query().unwrap_or("");
+    let session_id = match get_query_param(query, "sessionId") {
+        Some(value) => value,
+        None => state
+            .ws_session_counter
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string(),
+    };
+    let headers = headers
+        .iter()
+        .map(|(key, value)| {
+            let value = value.to_str().unwrap_or_default().to_owned();
+            (key.to_string(), value)
+        })
+        .collect::<HashMap<_, _>>();
+    let open = serde_json::json!({
+        "id": session_id,
+        "path": uri.path(),
+        "query": query,
+        "headers": headers,
+        "principal": principal,
+    });
 
-fn add_cors_headers(headers: &mut HeaderMap) {
-    headers.insert("Access-Control-Allow-Origin", HeaderValue::from_static("*"));
-    headers.insert(
-        "Access-Control-Allow-Methods",
-        HeaderValue::from_static("GET, POST, OPTIONS"),
-    );
-    headers.insert(
-        "Access-Control-Allow-Headers",
-        HeaderValue::from_static("content-type, authorization"),
-    );
-    headers.insert("Access-Control-Max-Age", HeaderValue::from_static("86400"));
+    ws_handler::handle_upgrade(upgrade, session_id, open, state.ws_sessions, state.events)
 }
 
 fn get_query_param(query: &str, key: &str) -> Option<String> {
@@ -463,3 +1188,213 @@ fn get_query_param(query: &str, key: &str) -> Option<String> {
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn incoming(content_type: Option<&str>, body: &'static [u8]) -> serde_json::Value {
+        let headers = content_type
+            .map(|value| ("content-type".to_owned(), value.to_owned()))
+            .into_iter()
+            .collect();
+        let request = HttpIncomingRequest {
+            id: "1".to_owned(),
+            method: "POST".to_owned(),
+            path: "/upload".to_owned(),
+            query: String::new(),
+            headers,
+            body: Bytes::from_static(body),
+            route: None,
+            params: serde_json::Map::new(),
+            principal: serde_json::Value::Null,
+        };
+        request.to_json()
+    }
+
+    #[test]
+    fn text_bodies_stay_text() {
+        for content_type in [
+            None,
+            Some("application/json; charset=utf-8"),
+            Some("text/csv"),
+            Some("application/problem+json"),
+        ] {
+            let request = incoming(content_type, "{\"name\":\"Иван\"}".as_bytes());
+            assert_eq!(request["body"], "{\"name\":\"Иван\"}", "{content_type:?}");
+            assert_eq!(request["isBase64"], false);
+        }
+    }
+
+    #[test]
+    fn binary_bodies_are_base64() {
+        let request = incoming(Some("image/png"), b"\x89PNG");
+        assert_eq!(request["body"], "iVBORw==");
+        assert_eq!(request["isBase64"], true);
+
+        // Declared as text, but not valid UTF-8.
+        let request = incoming(Some("text/plain"), &[0xff, 0xfe, 0x00]);
+        assert_eq!(request["body"], "//4A");
+        assert_eq!(request["isBase64"], true);
+    }
+
+    fn answer(
+        response: oneshot::Sender<HttpResponse>,
+        after: Duration,
+    ) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            tokio::time::sleep(after).await;
+            let _ = response.send(HttpResponse {
+                status: 200,
+                headers: HashMap::new(),
+                body: Body::from("done"),
+                default_content_type: "text/plain",
+            });
+        })
+    }
+
+    #[test]
+    fn informational_statuses_other_than_102_are_rejected() {
+        for status in [100, 101, 103, 199, 99, 600] {
+            assert!(check_status(status).is_err(), "{status}");
+        }
+        for status in [102, 200, 204, 404, 599] {
+            assert_eq!(check_status(status).unwrap(), status as u16);
+        }
+    }
+
+    #[tokio::test]
+    async fn channel_body_streams_chunks_until_the_sender_is_dropped() {
+        let (tx, body) = channel_body();
+        tx.send(Bytes::from_static(b"id;name\n")).unwrap();
+        tx.send(Bytes::from_static(b"1;first\n")).unwrap();
+        let reader = tokio::spawn(to_bytes(body, usize::MAX));
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!reader.is_finished());
+
+        tx.send(Bytes::from_static(b"2;second\n")).unwrap();
+        drop(tx);
+        let body = reader.await.unwrap().unwrap();
+        assert_eq!(body, "id;name\n1;first\n2;second\n");
+    }
+
+    #[tokio::test]
+    async fn dropped_request_leaves_the_response_map() {
+        let response_map = ResponseMap::default();
+        let (sender, _response) = oneshot::channel();
+        let (extend, _extensions) = mpsc::unbounded_channel();
+        let pending = PendingResponse { sender, extend };
+        response_map.lock().await.insert("1".to_owned(), pending);
+
+        drop(PendingGuard {
+            id: Some("1".to_owned()),
+            response_map: response_map.clone(),
+            events: None,
+        });
+        for _ in 0..100 {
+            if response_map.lock().await.is_empty() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("pending request was not removed");
+    }
+
+    #[tokio::test]
+    async fn client_disconnect_raises_http_cancelled() {
+        use tokio::io::AsyncWriteExt;
+
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let events: EventSink = Arc::new(move |event: &str, payload: serde_json::Value| {
+            events_tx.send((event.to_owned(), payload)).is_ok()
+        });
+        let response_map = ResponseMap::default();
+        let state = HttpAppState {
+            response_map: response_map.clone(),
+            counter: Arc::new(AtomicU64::new(1)),
+            connection: None,
+            events: Some(events),
+            sse_sessions: SseSessions::default(),
+            sse_session_counter: Arc::new(AtomicU64::new(1)),
+            sse_keep_alive: None,
+            ws_sessions: WsSessions::default(),
+            ws_session_counter: Arc::new(AtomicU64::new(1)),
+            scheme: "http",
+            response_timeout: None,
+            routes: Arc::default(),
+            auth: Arc::default(),
+        };
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (_shutdown, shutdown_rx) = oneshot::channel();
+        tokio::spawn(serve(listener, router(state, Arc::default()), shutdown_rx));
+
+        let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /orders HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .await
+            .unwrap();
+        let (event, request) = events_rx.recv().await.unwrap();
+        assert_eq!(event, "HTTP");
+        let id = request["id"].as_str().unwrap().to_owned();
+        assert!(response_map.lock().await.contains_key(&id));
+
+        drop(client);
+        let (event, payload) = tokio::time::timeout(Duration::from_secs(5), events_rx.recv())
+            .await
+            .expect("HTTP_CANCELLED was not raised")
+            .unwrap();
+        assert_eq!(event, "HTTP_CANCELLED");
+        assert_eq!(
+            payload,
+            serde_json::json!({ "id": id, "reason": "disconnect" })
+        );
+        assert!(response_map.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn response_wait_ends_at_the_deadline() {
+        let (response_tx, response_rx) = oneshot::channel();
+        let (_extend_tx, extend_rx) = mpsc::unbounded_channel();
+        answer(response_tx, Duration::from_millis(300));
+
+        let timeout = Some(Duration::from_millis(50));
+        assert!(wait_for_response(response_rx, extend_rx, timeout)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn extended_deadline_lets_the_response_arrive() {
+        let (response_tx, response_rx) = oneshot::channel();
+        let (extend_tx, extend_rx) = mpsc::unbounded_channel();
+        answer(response_tx, Duration::from_millis(150));
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            extend_tx.send(Some(Duration::from_millis(500))).unwrap();
+        });
+
+        let timeout = Some(Duration::from_millis(50));
+        let response = wait_for_response(response_rx, extend_rx, timeout).await;
+        let body = to_bytes(response.unwrap().unwrap().body, usize::MAX).await;
+        assert_eq!(body.unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn extension_without_timeout_renews_the_server_timeout() {
+        let (response_tx, response_rx) = oneshot::channel();
+        let (extend_tx, extend_rx) = mpsc::unbounded_channel();
+        answer(response_tx, Duration::from_millis(120));
+        tokio::spawn(async move {
+            for _ in 0..3 {
+                tokio::time::sleep(Duration::from_millis(40)).await;
+                let _ = extend_tx.send(None);
+            }
+        });
+
+        let timeout = Some(Duration::from_millis(80));
+        assert!(wait_for_response(response_rx, extend_rx, timeout)
+            .await
+            .is_ok());
+    }
+}
@@ -0,0 +1,548 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use bytes::Bytes;
+use futures_util::stream;
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+use crate::events::{notify, EventSink};
+
+/// A comment line: ignored by `EventSource`, but keeps proxies from closing an idle stream.
+const KEEP_ALIVE_COMMENT: &str = ": keep-alive\n\n";
+/// How long the replay buffer of a disconnected session waits for the client to reconnect.
+const REPLAY_RETENTION: Duration = Duration::from_secs(300);
+
+pub(super) type SseSessions = Arc<Mutex<SseHub>>;
+
+/// Open SSE sessions, their channels and the events they may need to replay
+/// after a reconnect.
+#[derive(Default)]
+pub(super) struct SseHub {
+    /// Deliver formatted events to the stream of each open session.
+    senders: HashMap<String, mpsc::UnboundedSender<String>>,
+    /// Known sessions, open or waiting for a reconnect.
+    replay: HashMap<String, ReplayBuffer>,
+    /// Session ids by channel name.
+    channels: HashMap<String, HashSet<String>>,
+    /// Events kept per session; `0` disables replay.
+    replay_size: usize,
+}
+
+#[derive(Default)]
+struct ReplayBuffer {
+    /// Sent events with their ids, oldest first.
+    events: VecDeque<(Option<String>, String)>,
+    /// Set while no stream is attached to the session.
+    detached_at: Option<Instant>,
+    /// Who opened the session; only the same principal may reconnect to it.
+    principal: serde_json::Value,
+}
+
+/// Why a stream cannot be attached to the session.
+#[derive(Debug, PartialEq)]
+pub(super) enum OpenError {
+    /// Another stream is still attached to the session.
+    Connected,
+    /// The session was opened by another principal.
+    Forbidden,
+}
+
+impl SseHub {
+    pub(super) fn set_replay_size(&mut self, size: usize) {
+        self.replay_size = size;
+    }
+
+    /// Attaches a stream to the session. With `last_event_id` the events sent after
+    /// that id are delivered again, if the id is still in the replay buffer.
+    pub(super) fn open(
+        &mut self,
+        session_id: &str,
+        principal: &serde_json::Value,
+        tx: mpsc::UnboundedSender<String>,
+        last_event_id: Option<&str>,
+    ) -> Result<(), OpenError> {
+        self.expire();
+        self.detach(session_id);
+        if self.senders.contains_key(session_id) {
+            return Err(OpenError::Connected);
+        }
+
+        let buffer = self
+            .replay
+            .entry(session_id.to_owned())
+            .or_insert_with(|| ReplayBuffer {
+                principal: principal.clone(),
+                ..ReplayBuffer::default()
+            });
+        if buffer.principal != *principal {
+            return Err(OpenError::Forbidden);
+        }
+        buffer.detached_at = None;
+        if let Some(last_event_id) = last_event_id {
+            let position = buffer
+                .events
+                .iter()
+                .position(|(id, _)| id.as_deref() == Some(last_event_id));
+            if let Some(position) = position {
+                for (_, frame) in buffer.events.iter().skip(position + 1) {
+                    let _ = tx.send(frame.clone());
+                }
+            }
+        }
+        self.senders.insert(session_id.to_owned(), tx);
+        Ok(())
+    }
+
+    /// Events for a disconnected session go to its replay buffer until it expires;
+    /// `Ok(false)` tells that the client is gone and the event was only buffered.
+    pub(super) fn send(
+        &mut self,
+        session_id: &str,
+        id: Option<&str>,
+        frame: String,
+    ) -> Result<bool, Box<dyn Error>> {
+        self.expire();
+        if !self.replay.contains_key(session_id) {
+            return Err("SSE сессия не найдена".to_owned().into());
+        }
+        self.deliver(session_id, id, frame)
+            .ok_or_else(|| "Не удалось отправить SSE событие".into())
+    }
+
+    /// Sends the event to every session of the channel, buffering it for the
+    /// disconnected ones; returns how many sessions got or buffered it.
+    pub(super) fn send_to_channel(
+        &mut self,
+        channel: &str,
+        id: Option<&str>,
+        frame: String,
+    ) -> usize {
+        self.expire();
+        let sessions = self
+            .channels
+            .get(channel)
+            .map(|sessions| sessions.iter().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        sessions
+            .iter()
+            .filter(|session_id| self.deliver(session_id, id, frame.clone()).is_some())
+            .count()
+    }
+
+    /// Sends the event to every session like `send_to_channel`.
+    pub(super) fn broadcast(&mut self, id: Option<&str>, frame: String) -> usize {
+        self.expire();
+        let sessions = self.replay.keys().cloned().collect::<Vec<_>>();
+        sessions
+            .iter()
+            .filter(|session_id| self.deliver(session_id, id, frame.clone()).is_some())
+            .count()
+    }
+
+    /// Memberships live as long as the session, reconnects included.
+    pub(super) fn join(&mut self, session_id: &str, channel: &str) -> Result<(), Box<dyn Error>> {
+        self.expire();
+        if !self.replay.contains_key(session_id) {
+            return Err("SSE сессия не найдена".to_owned().into());
+        }
+        self.channels
+            .entry(channel.to_owned())
+            .or_default()
+            .insert(session_id.to_owned());
+        Ok(())
+    }
+
+    pub(super) fn leave(&mut self, session_id: &str, channel: &str) {
+        if let Some(sessions) = self.channels.get_mut(channel) {
+            sessions.remove(session_id);
+            if sessions.is_empty() {
+                self.channels.remove(channel);
+            }
+        }
+    }
+
+    /// Ends the stream of the session and forgets its events and channels.
+    pub(super) fn close(&mut self, session_id: &str) {
+        self.senders.remove(session_id);
+        self.replay.remove(session_id);
+        self.channels.retain(|_, sessions| {
+            sessions.remove(session_id);
+            !sessions.is_empty()
+        });
+    }
+
+    pub(super) fn clear(&mut self) {
+        self.senders.clear();
+        self.replay.clear();
+        self.channels.clear();
+    }
+
+    /// Called when a stream ends; keeps the replay buffer for a reconnect.
+    fn detach(&mut self, session_id: &str) {
+        self.expire();
+        // The id may already belong to a newer stream that reused it via `sessionId`.
+        if !self
+            .senders
+            .get(session_id)
+            .is_some_and(|tx| tx.is_closed())
+        {
+            return;
+        }
+        self.senders.remove(session_id);
+        if let Some(buffer) = self.replay.get_mut(session_id) {
+            buffer.detached_at = Some(Instant::now());
+        }
+    }
+
+    /// Forgets sessions that stayed disconnected longer than `REPLAY_RETENTION`.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired = self
+            .replay
+            .iter()
+            .filter(|(_, buffer)| {
+                buffer
+                    .detached_at
+                    .is_some_and(|detached_at| now - detached_at >= REPLAY_RETENTION)
+            })
+            .map(|(id, _)| id.clone())
+            .collect::<Vec<_>>();
+        for id in expired {
+            self.close(&id);
+        }
+    }
+
+    /// Sends the event to the stream, or only buffers it while the session is detached.
+    /// Returns whether the stream got it, `None` when the event is lost.
+    fn deliver(&mut self, session_id: &str, id: Option<&str>, frame: String) -> Option<bool> {
+        let sent = self
+            .senders
+            .get(session_id)
+            .is_some_and(|sender| sender.send(frame.clone()).is_ok());
+        if !sent {
+            self.detach(session_id);
+            if self.replay_size == 0 {
+                return None;
+            }
+        }
+        let Some(buffer) = self.replay.get_mut(session_id) else {
+            return sent.then_some(true);
+        };
+        if self.replay_size > 0 {
+            if buffer.events.len() == self.replay_size {
+                buffer.events.pop_front();
+            }
+            buffer.events.push_back((id.map(str::to_owned), frame));
+        }
+        Some(sent)
+    }
+}
+
+/// Formats an SSE event; `data` may span several lines.
+pub(super) fn format_event(
+    event: &str,
+    data: &str,
+    id: Option<&str>,
+    retry: Option<u32>,
+) -> String {
+    let mut out = String::new();
+    if let Some(id) = id {
+        out.push_str("id: ");
+        out.push_str(id);
+        out.push('\n');
+    }
+    if let Some(retry) = retry {
+        out.push_str(&format!("retry: {retry}\n"));
+    }
+    out.push_str("event: ");
+    out.push_str(event);
+    out.push('\n');
+    if data.is_empty() {
+        out.push_str("data:\n");
+    } else {
+        for line in data.lines() {
+            out.push_str("data: ");
+            out.push_str(line);
+            out.push('\n');
+        }
+    }
+    out.push('\n');
+    out
+}
+
+/// Body of the `/sse` response: the events sent to `rx`, interleaved with keep-alive
+/// comments every `keep_alive`. The stream ends once the sender leaves `sessions`;
+/// when it ends or the client goes away, the session is removed and `SSE_CLOSE` raised.
+pub(super) fn event_stream(
+    session_id: String,
+    rx: mpsc::UnboundedReceiver<String>,
+    sessions: SseSessions,
+    keep_alive: Option<Duration>,
+    events: Option<EventSink>,
+) -> Body {
+    let keep_alive = keep_alive.map(|period| {
+        let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        interval
+    });
+    let state = SessionStream {
+        rx,
+        keep_alive,
+        _close: CloseGuard {
+            session_id,
+            sessions,
+            events,
+        },
+    };
+    let stream = stream::unfold(state, |mut state| async move {
+        let chunk = tokio::select! {
+            biased;
+            item = state.rx.recv() => item?,
+            _ = tick(&mut state.keep_alive) => KEEP_ALIVE_COMMENT.to_owned(),
+        };
+        Some((Ok::<Bytes, std::io::Error>(Bytes::from(chunk)), state))
+    });
+    Body::from_stream(stream)
+}
+
+/// Fields drop in order: `rx` is closed before the guard looks at the session.
+struct SessionStream {
+    rx: mpsc::UnboundedReceiver<String>,
+    keep_alive: Option<Interval>,
+    _close: CloseGuard,
+}
+
+struct CloseGuard {
+    session_id: String,
+    sessions: SseSessions,
+    events: Option<EventSink>,
+}
+
+impl Drop for CloseGuard {
+    fn drop(&mut self) {
+        let Ok(handle) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let session_id = std::mem::take(&mut self.session_id);
+        let sessions = self.sessions.clone();
+        let events = self.events.take();
+        handle.spawn(async move {
+            sessions.lock().await.detach(&session_id);
+            let payload = serde_json::json!({ "id": session_id });
+            notify(events.as_ref(), "SSE_CLOSE", payload, true).await;
+        });
+    }
+}
+
+async fn tick(keep_alive: &mut Option<Interval>) {
+    match keep_alive {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+    use std::sync::Mutex as StdMutex;
+
+    type EventLog = Arc<StdMutex<Vec<(String, serde_json::Value)>>>;
+
+    const NO_PRINCIPAL: serde_json::Value = serde_json::Value::Null;
+
+    fn open(keep_alive: Option<Duration>) -> (SseSessions, EventLog, Body) {
+        let sessions = SseSessions::default();
+        let log = EventLog::default();
+        let events: EventSink = {
+            let log = log.clone();
+            Arc::new(move |event: &str, payload| {
+                log.lock().unwrap().push((event.to_owned(), payload));
+                true
+            })
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        sessions
+            .try_lock()
+            .unwrap()
+            .open("s1", &NO_PRINCIPAL, tx, None)
+            .unwrap();
+        let body = event_stream(
+            "s1".to_owned(),
+            rx,
+            sessions.clone(),
+            keep_alive,
+            Some(events),
+        );
+        (sessions, log, body)
+    }
+
+    async fn next_chunk(body: &mut Body) -> Option<String> {
+        let frame = body.frame().await?.unwrap();
+        Some(String::from_utf8(frame.into_data().unwrap().to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_removes_the_session() {
+        let (sessions, log, body) = open(None);
+        drop(body);
+
+        for _ in 0..100 {
+            if !log.lock().unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(
+            log.lock().unwrap().as_slice(),
+            [("SSE_CLOSE".to_owned(), serde_json::json!({ "id": "s1" }))]
+        );
+        assert!(sessions.lock().await.senders.is_empty());
+    }
+
+    #[tokio::test]
+    async fn idle_stream_gets_keep_alive_comments() {
+        let (sessions, _log, mut body) = open(Some(Duration::from_millis(20)));
+        assert_eq!(next_chunk(&mut body).await.unwrap(), KEEP_ALIVE_COMMENT);
+
+        let mut hub = sessions.lock().await;
+        hub.send("s1", None, "data: x\n\n".to_owned()).unwrap();
+        hub.close("s1");
+        drop(hub);
+        assert_eq!(next_chunk(&mut body).await.unwrap(), "data: x\n\n");
+        assert!(next_chunk(&mut body).await.is_none());
+    }
+
+    #[test]
+    fn formats_id_retry_and_multiline_data() {
+        assert_eq!(
+            format_event("order", "a\nb", Some("7"), Some(5000)),
+            "id: 7\nretry: 5000\nevent: order\ndata: a\ndata: b\n\n"
+        );
+        assert_eq!(
+            format_event("message", "", None, None),
+            "event: message\ndata:\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn reconnect_replays_events_after_last_event_id() {
+        let mut hub = SseHub::default();
+        hub.set_replay_size(2);
+        let (tx, rx) = mpsc::unbounded_channel();
+        hub.open("s1", &NO_PRINCIPAL, tx, None).unwrap();
+        for id in ["1", "2", "3"] {
+            hub.send("s1", Some(id), format!("id: {id}\n\n")).unwrap();
+        }
+        drop(rx);
+        hub.detach("s1");
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.open("s1", &NO_PRINCIPAL, tx, Some("2")).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), "id: 3\n\n");
+        assert!(rx.try_recv().is_err());
+
+        // "1" no longer fits in the buffer: nothing to replay.
+        drop(rx);
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.open("s1", &NO_PRINCIPAL, tx, Some("1")).unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn events_sent_while_disconnected_are_replayed() {
+        let mut hub = SseHub::default();
+        hub.set_replay_size(10);
+        let (tx, rx) = mpsc::unbounded_channel();
+        hub.open("s1", &NO_PRINCIPAL, tx, None).unwrap();
+        assert!(hub.send("s1", Some("1"), "id: 1\n\n".to_owned()).unwrap());
+
+        // The stream is gone, but `CloseGuard` has not detached it yet. `false` shows
+        // 1C that the client is gone, while the events wait for a reconnect.
+        drop(rx);
+        assert!(!hub.send("s1", Some("2"), "id: 2\n\n".to_owned()).unwrap());
+        hub.detach("s1");
+        assert!(!hub.send("s1", Some("3"), "id: 3\n\n".to_owned()).unwrap());
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        hub.open("s1", &NO_PRINCIPAL, tx, Some("1")).unwrap();
+        assert_eq!(rx.try_recv().unwrap(), "id: 2\n\n");
+        assert_eq!(rx.try_recv().unwrap(), "id: 3\n\n");
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn sessions_cannot_be_taken_over() {
+        let mut hub = SseHub::default();
+        let owner = serde_json::json!({ "scheme": "bearer", "name": "svc" });
+        let (tx, rx) = mpsc::unbounded_channel();
+        hub.open("s1", &owner, tx, None).unwrap();
+
+        let (tx, _other) = mpsc::unbounded_channel();
+        assert_eq!(hub.open("s1", &owner, tx, None), Err(OpenError::Connected));
+
+        drop(rx);
+        let (tx, _other) = mpsc::unbounded_channel();
+        assert_eq!(
+            hub.open("s1", &NO_PRINCIPAL, tx, None),
+            Err(OpenError::Forbidden)
+        );
+        let (tx, _reconnected) = mpsc::unbounded_channel();
+        assert_eq!(hub.open("s1", &owner, tx, None), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn channel_and_broadcast_reach_open_and_detached_sessions() {
+        let mut hub = SseHub::default();
+        hub.set_replay_size(10);
+        let mut streams = Vec::new();
+        for session_id in ["a", "b", "c", "d"] {
+            let (tx, rx) = mpsc::unbounded_channel();
+            hub.open(session_id, &NO_PRINCIPAL, tx, None).unwrap();
+            streams.push(rx);
+        }
+        hub.join("a", "orders").unwrap();
+        hub.join("b", "orders").unwrap();
+        hub.join("d", "orders").unwrap();
+        assert!(hub.join("x", "orders").is_err());
+        hub.send("d", Some("0"), "seen\n\n".to_owned()).unwrap();
+        drop(streams.pop());
+        hub.detach("d");
+
+        assert_eq!(
+            hub.send_to_channel("orders", Some("1"), "o\n\n".to_owned()),
+            3
+        );
+        assert_eq!(hub.send_to_channel("stock", None, "s\n\n".to_owned()), 0);
+        hub.close("b");
+        assert_eq!(hub.broadcast(Some("2"), "all\n\n".to_owned()), 3);
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        hub.open("d", &NO_PRINCIPAL, tx, Some("0")).unwrap();
+        streams.push(rx);
+
+        let received = streams
+            .iter_mut()
+            .map(|rx| std::iter::from_fn(|| rx.try_recv().ok()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            received,
+            [
+                vec!["o\n\n".to_owned(), "all\n\n".to_owned()],
+                vec!["o\n\n".to_owned()],
+                vec!["all\n\n".to_owned()],
+                vec!["o\n\n".to_owned(), "all\n\n".to_owned()],
+            ]
+        );
+        assert_eq!(
+            hub.channels["orders"],
+            HashSet::from(["a".to_owned(), "d".to_owned()])
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use axum::serve::Listener;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const TLS_HANDSHAKE_TIMEOUT_SECS: u64 = 10;
+const ACCEPTED_QUEUE_SIZE: usize = 64;
+
+/// HTTPS listener for `axum::serve`. Handshakes run in their own tasks, so a slow or
+/// broken client never holds up the others; failed handshakes are dropped silently.
+pub(super) struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    accept_loop: JoinHandle<()>,
+}
+
+impl TlsListener {
+    pub(super) fn new(mut listener: TcpListener, acceptor: TlsAcceptor) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (tx, accepted) = mpsc::channel(ACCEPTED_QUEUE_SIZE);
+        let accept_loop = tokio::spawn(async move {
+            loop {
+                let (stream, addr) = Listener::accept(&mut listener).await;
+                let acceptor = acceptor.clone();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let timeout = Duration::from_secs(TLS_HANDSHAKE_TIMEOUT_SECS);
+                    if let Ok(Ok(stream)) =
+                        tokio::time::timeout(timeout, acceptor.accept(stream)).await
+                    {
+                        let _ = tx.send((stream, addr)).await;
+                    }
+                });
+            }
+        });
+        Ok(Self {
+            local_addr,
+            accepted,
+            accept_loop,
+        })
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        // Releases the port once the server stops.
+        self.accept_loop.abort();
+    }
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(accepted) => accepted,
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tls::{load_certified_key, ClientTlsOptions, ServerCertResolver};
+    use axum::routing::get;
+    use axum::Router;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use rustls::pki_types::ServerName;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    /// PEM of a CA and of a `localhost` certificate with its key issued by that CA.
+    fn make_pki() -> (String, String, String) {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        let key = KeyPair::generate().unwrap();
+        let cert = CertificateParams::new(vec!["localhost".to_owned()])
+            .unwrap()
+            .signed_by(&key, &ca)
+            .unwrap();
+        (ca.pem(), cert.pem(), key.serialize_pem())
+    }
+
+    async fn start_server(resolver: &Arc<ServerCertResolver>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(resolver.server_config().unwrap()));
+        let listener = TlsListener::new(listener, acceptor).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let app = Router::new().route("/", get(|| async { "secure" }));
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+        port
+    }
+
+    async fn get_root(port: u16, ca: &str) -> Result<String, std::io::Error> {
+        let options = serde_json::json!({ "caCert": ca });
+        let config = ClientTlsOptions::from_json(&options)
+            .unwrap()
+            .client_config()
+            .unwrap();
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(server_name, stream)
+            .await?;
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[tokio::test]
+    async fn serves_https_and_reloads_certificate() {
+        let (first_ca, cert, key) = make_pki();
+        let resolver = Arc::new(ServerCertResolver::new(
+            load_certified_key(&cert, &key).unwrap(),
+        ));
+        let port = start_server(&resolver).await;
+
+        let response = get_root(port, &first_ca).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("secure"), "{response}");
+
+        let (second_ca, cert, key) = make_pki();
+        resolver.set(load_certified_key(&cert, &key).unwrap());
+        assert!(get_root(port, &first_ca).await.is_err());
+        assert!(get_root(port, &second_ca).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn failed_handshake_does_not_stop_the_listener() {
+        let (ca, cert, key) = make_pki();
+        let resolver = Arc::new(ServerCertResolver::new(
+            load_certified_key(&cert, &key).unwrap(),
+        ));
+        let port = start_server(&resolver).await;
+
+        let mut plain = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        plain.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let _ = plain.read(&mut [0; 16]).await;
+
+        assert!(get_root(port, &ca).await.is_ok());
+    }
+}
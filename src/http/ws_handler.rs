@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::extract::ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade};
+use axum::http::Response;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::Instant;
+
+use crate::events::{notify, EventSink, CLOSE_HANDSHAKE_TIMEOUT_MS};
+
+/// RFC 6455 status codes reported in `WS_CLOSE` when the peer sent none.
+const CLOSE_NO_STATUS: u16 = 1005;
+const CLOSE_ABNORMAL: u16 = 1006;
+const CLOSE_GOING_AWAY: u16 = 1001;
+
+/// Open WebSocket sessions by id; the sender delivers frames to the session task.
+pub(super) type WsSessions = Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>>;
+
+/// Accepts the upgrade and serves the session in the background. `open` is the
+/// `WS_OPEN` payload, it already carries the session id.
+pub(super) fn handle_upgrade(
+    upgrade: WebSocketUpgrade,
+    session_id: String,
+    open: serde_json::Value,
+    sessions: WsSessions,
+    events: Option<EventSink>,
+) -> Response<Body> {
+    upgrade.on_upgrade(move |socket| run_session(socket, session_id, open, sessions, events))
+}
+
+async fn run_session(
+    mut socket: WebSocket,
+    session_id: String,
+    open: serde_json::Value,
+    sessions: WsSessions,
+    events: Option<EventSink>,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    sessions.lock().await.insert(session_id.clone(), tx);
+    notify(events.as_ref(), "WS_OPEN", open, true).await;
+
+    let mut peer_close = None;
+    // Set once the server has sent its close frame: the peer gets a bounded time to answer.
+    let mut close_deadline = None;
+    loop {
+        tokio::select! {
+            incoming = socket.recv() => match incoming {
+                Some(Ok(Message::Close(frame))) => {
+                    peer_close = Some(frame.map_or((CLOSE_NO_STATUS, String::new()), |frame| {
+                        (frame.code, frame.reason.to_string())
+                    }));
+                }
+                Some(Ok(Message::Text(text))) => {
+                    let payload = serde_json::json!({
+                        "id": session_id,
+                        "type": "text",
+                        "data": text.as_str(),
+                    });
+                    notify(events.as_ref(), "WS_MESSAGE", payload, true).await;
+                }
+                Some(Ok(Message::Binary(data))) => {
+                    let payload = serde_json::json!({
+                        "id": session_id,
+                        "type": "binary",
+                        "data": BASE64.encode(data),
+                    });
+                    notify(events.as_ref(), "WS_MESSAGE", payload, true).await;
+                }
+                Some(Ok(_)) => {}
+                Some(Err(_)) | None => break,
+            },
+            outgoing = rx.recv(), if close_deadline.is_none() && peer_close.is_none() => {
+                // The sender disappears when the session is dropped by `ОстановитьHTTP`.
+                let message = outgoing.unwrap_or_else(|| {
+                    close_message(CLOSE_GOING_AWAY, "Server stopped")
+                });
+                if matches!(message, Message::Close(_)) {
+                    let timeout = Duration::from_millis(CLOSE_HANDSHAKE_TIMEOUT_MS);
+                    close_deadline = Some(Instant::now() + timeout);
+                }
+                if socket.send(message).await.is_err() {
+                    break;
+                }
+            }
+            _ = tokio::time::sleep_until(close_deadline.unwrap_or_else(Instant::now)),
+                if close_deadline.is_some() => break,
+        }
+    }
+    drop(rx);
+
+    {
+        let mut map = sessions.lock().await;
+        // The id may already belong to a newer session that reused it via `sessionId`.
+        if map.get(&session_id).is_some_and(|tx| tx.is_closed()) {
+            map.remove(&session_id);
+        }
+    }
+
+    let (code, reason) = peer_close.unwrap_or((CLOSE_ABNORMAL, String::new()));
+    let payload = serde_json::json!({
+        "id": session_id,
+        "code": code,
+        "reason": reason,
+    });
+    notify(events.as_ref(), "WS_CLOSE", payload, true).await;
+}
+
+pub(super) fn close_message(code: u16, reason: &str) -> Message {
+    Message::Close(Some(CloseFrame {
+        code,
+        reason: reason.into(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::extract::State;
+    use axum::routing::get;
+    use axum::Router;
+    use futures_util::{SinkExt, StreamExt};
+    use std::sync::Mutex as StdMutex;
+    use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+    use tokio_tungstenite::tungstenite::Message as ClientMessage;
+
+    type EventLog = Arc<StdMutex<Vec<(String, serde_json::Value)>>>;
+
+    /// Serves `/ws` with session id `s1` and records every event.
+    async fn start_server() -> (String, WsSessions, EventLog) {
+        let sessions = WsSessions::default();
+        let log = EventLog::default();
+        let events: EventSink = {
+            let log = log.clone();
+            Arc::new(move |event: &str, payload| {
+                log.lock().unwrap().push((event.to_owned(), payload));
+                true
+            })
+        };
+
+        let app = Router::new()
+            .route(
+                "/ws",
+                get(
+                    |State((sessions, events)): State<(WsSessions, EventSink)>,
+                     upgrade: WebSocketUpgrade| async move {
+                        let open = serde_json::json!({ "id": "s1" });
+                        handle_upgrade(upgrade, "s1".to_owned(), open, sessions, Some(events))
+                    },
+                ),
+            )
+            .with_state((sessions.clone(), events));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let _ = axum::serve(listener, app).await;
+        });
+
+        (format!("ws://127.0.0.1:{port}/ws"), sessions, log)
+    }
+
+    async fn wait_for_event(log: &EventLog, event: &str) -> serde_json::Value {
+        for _ in 0..200 {
+            let found = log
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|(name, _)| name == event)
+                .map(|(_, payload)| payload.clone());
+            if let Some(payload) = found {
+                return payload;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("no {event} event");
+    }
+
+    async fn session_sender(sessions: &WsSessions) -> mpsc::UnboundedSender<Message> {
+        sessions.lock().await.get("s1").cloned().expect("session")
+    }
+
+    #[tokio::test]
+    async fn relays_messages_both_ways() {
+        let (address, sessions, log) = start_server().await;
+        let (mut client, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+        wait_for_event(&log, "WS_OPEN").await;
+
+        client
+            .send(ClientMessage::Text("hello".into()))
+            .await
+            .unwrap();
+        client
+            .send(ClientMessage::Binary(vec![1, 2, 3].into()))
+            .await
+            .unwrap();
+        session_sender(&sessions)
+            .await
+            .send(Message::Text("reply".into()))
+            .unwrap();
+
+        let reply = client.next().await.unwrap().unwrap();
+        assert_eq!(reply.into_text().unwrap().as_str(), "reply");
+
+        for _ in 0..200 {
+            if log.lock().unwrap().len() >= 3 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let messages = log
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(name, _)| name == "WS_MESSAGE")
+            .map(|(_, payload)| payload.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                serde_json::json!({ "id": "s1", "type": "text", "data": "hello" }),
+                serde_json::json!({ "id": "s1", "type": "binary", "data": "AQID" }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn peer_close_is_reported_and_session_removed() {
+        let (address, sessions, log) = start_server().await;
+        let (mut client, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+        wait_for_event(&log, "WS_OPEN").await;
+
+        client
+            .close(Some(tokio_tungstenite::tungstenite::protocol::CloseFrame {
+                code: CloseCode::Away,
+                reason: "bye".into(),
+            }))
+            .await
+            .unwrap();
+
+        let close = wait_for_event(&log, "WS_CLOSE").await;
+        assert_eq!(
+            close,
+            serde_json::json!({ "id": "s1", "code": 1001, "reason": "bye" })
+        );
+        assert!(sessions.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn server_close_completes_the_handshake() {
+        let (address, sessions, log) = start_server().await;
+        let (mut client, _) = tokio_tungstenite::connect_async(address).await.unwrap();
+        wait_for_event(&log, "WS_OPEN").await;
+
+        session_sender(&sessions)
+            .await
+            .send(close_message(4000, "done"))
+            .unwrap();
+
+        let Some(Ok(ClientMessage::Close(Some(frame)))) = client.next().await else {
+            panic!("expected a close frame");
+        };
+        assert_eq!(
+            (u16::from(frame.code), frame.reason.as_str()),
+            (4000, "done")
+        );
+        while client.next().await.is_some() {}
+
+        let close = wait_for_event(&log, "WS_CLOSE").await;
+        assert_eq!(close["code"], 4000);
+    }
+}
@@ -1,8 +1,13 @@
 mod addin_error;
+mod allow_list;
+mod events;
 mod http;
 mod mcp;
+mod proxy;
+mod tls;
 mod ws;
 mod ws_client;
+mod ws_deflate;
 use std::{
     collections::HashMap,
     error::Error,
@@ -36,6 +41,54 @@ pub(crate) fn parse_headers(
         .collect())
 }
 
+pub(crate) fn option_str<'a>(
+    obj: &'a serde_json::Map<String, serde_json::Value>,
+    key: &str,
+) -> Result<Option<&'a str>, Box<dyn Error>> {
+    match obj.get(key) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::String(value)) if value.trim().is_empty() => Ok(None),
+        Some(serde_json::Value::String(value)) => Ok(Some(value.as_str())),
+        Some(_) => Err(format!("Параметр {key} должен быть строкой").into()),
+    }
+}
+
+pub(crate) fn option_bool(
+    obj: &serde_json::Map<String, serde_json::Value>,
+    key: &str,
+) -> Result<Option<bool>, Box<dyn Error>> {
+    match obj.get(key) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(serde_json::Value::Bool(value)) => Ok(Some(*value)),
+        Some(_) => Err(format!("Параметр {key} должен быть булевым").into()),
+    }
+}
+
+pub(crate) fn option_u64(
+    obj: &serde_json::Map<String, serde_json::Value>,
+    key: &str,
+) -> Result<Option<u64>, Box<dyn Error>> {
+    match obj.get(key) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(value) => value.as_u64().map(Some).ok_or_else(|| {
+            format!("Параметр {key} должен быть неотрицательным целым числом").into()
+        }),
+    }
+}
+
+pub(crate) fn option_f64(
+    obj: &serde_json::Map<String, serde_json::Value>,
+    key: &str,
+) -> Result<Option<f64>, Box<dyn Error>> {
+    match obj.get(key) {
+        None | Some(serde_json::Value::Null) => Ok(None),
+        Some(value) => value
+            .as_f64()
+            .map(Some)
+            .ok_or_else(|| format!("Параметр {key} должен быть числом").into()),
+    }
+}
+
 pub static PLATFORM_CAPABILITIES: AtomicI32 = AtomicI32::new(-1);
 
 unsafe fn cstr1c_to_string(name: *const u16) -> String {
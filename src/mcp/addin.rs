@@ -12,8 +12,9 @@ use tokio::sync::{oneshot, Mutex};
 
 use super::registry::Registry;
 use super::server::{
-    parse_allow_list, start_mcp_server, AllowList, McpResponse, McpServerInfo, McpServerState,
+    parse_allow_list, start_mcp_server, McpResponse, McpServerInfo, McpServerState,
 };
+use crate::allow_list::AllowList;
 use crate::{addin_error::report_platform_error, parse_headers, VERSION};
 pub struct McpAddIn {
     pub(super) connection: Option<&'static addin1c::Connection>,
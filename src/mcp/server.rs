@@ -41,6 +41,8 @@ use tokio::sync::{mpsc, oneshot, Mutex};
 use tokio_util::sync::CancellationToken;
 use tower::{Layer, Service};
 
+use crate::allow_list::AllowList;
+
 use super::registry::{Registry, ResolveResourceError, ResolvedResource, ToolEntry};
 
 type ProgressResetMap = Arc<Mutex<HashMap<String, HashMap<String, mpsc::Sender<()>>>>>;
@@ -117,32 +119,6 @@ impl McpServerState {
     }
 }
 
-#[derive(Debug, Clone)]
-pub(super) enum AllowList {
-    Any,
-    List(HashSet<String>),
-}
-
-impl AllowList {
-    pub(super) fn default_local() -> Self {
-        let mut set = HashSet::new();
-        set.insert("http://localhost".to_owned());
-        set.insert("http://127.0.0.1".to_owned());
-        AllowList::List(set)
-    }
-
-    fn allows(&self, origin: &str) -> bool {
-        match self {
-            AllowList::Any => true,
-            AllowList::List(list) => list.contains(origin),
-        }
-    }
-
-    fn is_any(&self) -> bool {
-        matches!(self, AllowList::Any)
-    }
-}
-
 pub(super) fn parse_allow_list(raw: &str) -> Result<AllowList, Box<dyn Error>> {
     let trimmed = raw.trim();
     if trimmed.is_empty() {
@@ -152,14 +128,7 @@ pub(super) fn parse_allow_list(raw: &str) -> Result<AllowList, Box<dyn Error>> {
         return Ok(AllowList::Any);
     }
     let list = serde_json::from_str::<Vec<String>>(trimmed)?;
-    if list.iter().any(|value| value == "*") {
-        return Ok(AllowList::Any);
-    }
-    let set = list
-        .into_iter()
-        .filter(|value| !value.trim().is_empty())
-        .collect::<HashSet<_>>();
-    Ok(AllowList::List(set))
+    Ok(AllowList::from_origins(list))
 }
 
 #[derive(Debug)]
@@ -190,6 +159,7 @@ struct PreparedToolCall {
     arguments: serde_json::Value,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub(super) fn start_mcp_server(
     runtime: Arc<tokio::runtime::Runtime>,
     address: SocketAddr,
@@ -257,6 +227,7 @@ pub(super) fn start_mcp_server(
 }
 
 #[cfg(test)]
+#[allow(clippy::too_many_arguments)]
 fn start_mcp_server_with_listener(
     runtime: Arc<tokio::runtime::Runtime>,
     listener: std::net::TcpListener,
@@ -287,19 +258,20 @@ fn start_mcp_server_with_listener(
         progress_resets,
     });
 
+    let mut service_config = StreamableHttpServerConfig::default();
+    service_config.stateful_mode = true;
+    service_config.json_response = false;
+    service_config.sse_keep_alive = None;
+    service_config.sse_retry = None;
+    service_config.cancellation_token = CancellationToken::new();
+
     let service = StreamableHttpService::new(
         {
             let handler = handler.clone();
             move || Ok(handler.clone())
         },
         Arc::new(LocalSessionManager::default()),
-        StreamableHttpServerConfig {
-            stateful_mode: true,
-            json_response: false,
-            sse_keep_alive: None,
-            sse_retry: None,
-            cancellation_token: CancellationToken::new(),
-        },
+        service_config,
     );
 
     let service = AllowListLayer { allow_list }.layer(service);
@@ -684,7 +656,9 @@ impl McpBridgeHandler {
         reset_rx: Option<mpsc::Receiver<()>>,
     ) -> Result<McpResponse, McpError> {
         let Some(mut reset_rx) = reset_rx else {
-            return self.wait_for_response_without_progress(request_id, rx).await;
+            return self
+                .wait_for_response_without_progress(request_id, rx)
+                .await;
         };
         let mut rx = rx;
         loop {
@@ -999,7 +973,7 @@ impl McpBridgeHandler {
 }
 
 fn peer_id(peer: &ClientSink) -> Option<usize> {
-    peer.peer_info().map(|info| info as *const _ as usize)
+    peer.peer_info().map(|info| &*info as *const _ as usize)
 }
 
 impl ServerHandler for McpBridgeHandler {
@@ -1051,21 +1025,19 @@ impl ServerHandler for McpBridgeHandler {
         }
     }
 
-    fn list_tools(
+    async fn list_tools(
         &self,
         _request: Option<PaginatedRequestParams>,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<ListToolsResult, McpError>> + Send + '_ {
-        async move {
-            let tools = {
-                let guard = self
-                    .registry
-                    .read()
-                    .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
-                guard.list_tools()
-            };
-            Ok(ListToolsResult::with_all_items(tools))
-        }
+    ) -> Result<ListToolsResult, McpError> {
+        let tools = {
+            let guard = self
+                .registry
+                .read()
+                .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
+            guard.list_tools()
+        };
+        Ok(ListToolsResult::with_all_items(tools))
     }
 
     fn get_tool(&self, name: &str) -> Option<rmcp::model::Tool> {
@@ -1075,384 +1047,337 @@ impl ServerHandler for McpBridgeHandler {
             .and_then(|guard| guard.get_tool(name).map(|entry| entry.tool))
     }
 
-    fn call_tool(
+    async fn call_tool(
         &self,
         request: CallToolRequestParams,
         context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<CallToolResult, McpError>> + Send + '_ {
-        async move {
-            let prepared = self.prepare_tool_call(&request)?;
-            if matches!(prepared.task_support, TaskSupport::Required) {
-                return Err(McpError::invalid_params(
-                    "Tool requires task-based invocation",
-                    None,
-                ));
-            }
-
-            let progress_token =
-                Self::request_progress_token(&request, &context.meta).map(progress_token_to_json);
-            let payload = Self::build_tool_call_payload(
-                request.name.as_ref(),
-                prepared.arguments,
-                progress_token,
+    ) -> Result<CallToolResult, McpError> {
+        let prepared = self.prepare_tool_call(&request)?;
+        if matches!(prepared.task_support, TaskSupport::Required) {
+            return Err(McpError::invalid_params(
+                "Tool requires task-based invocation",
                 None,
-            );
-
-            self.dispatch_request("MCP_TOOL_CALL", payload).await
+            ));
         }
+
+        let progress_token =
+            Self::request_progress_token(&request, &context.meta).map(progress_token_to_json);
+        let payload = Self::build_tool_call_payload(
+            request.name.as_ref(),
+            prepared.arguments,
+            progress_token,
+            None,
+        );
+
+        self.dispatch_request("MCP_TOOL_CALL", payload).await
     }
 
-    fn enqueue_task(
+    async fn enqueue_task(
         &self,
         request: CallToolRequestParams,
         context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<rmcp::model::CreateTaskResult, McpError>> + Send + '_
-    {
-        async move {
-            let prepared = self.prepare_tool_call(&request)?;
-            if matches!(prepared.task_support, TaskSupport::Forbidden) {
-                return Err(McpError::invalid_params(
-                    "Tool does not support task-based invocation",
-                    None,
-                ));
-            }
+    ) -> Result<rmcp::model::CreateTaskResult, McpError> {
+        let prepared = self.prepare_tool_call(&request)?;
+        if matches!(prepared.task_support, TaskSupport::Forbidden) {
+            return Err(McpError::invalid_params(
+                "Tool does not support task-based invocation",
+                None,
+            ));
+        }
 
-            let task = self.create_task_entry(&request, &context).await?;
-            let progress_token = self
-                .task_progress_token(task.task_id.as_str())
-                .await
-                .map(progress_token_to_json);
-            let payload = Self::build_tool_call_payload(
-                request.name.as_ref(),
-                prepared.arguments,
-                progress_token,
-                Some(task.task_id.as_str()),
-            );
-            if let Err(err) = self.emit_event("MCP_TOOL_CALL", payload) {
-                let mut tasks = self.tasks.lock().await;
-                tasks.remove(task.task_id.as_str());
-                return Err(err);
-            }
-            Ok(rmcp::model::CreateTaskResult::new(task))
+        let task = self.create_task_entry(&request, &context).await?;
+        let progress_token = self
+            .task_progress_token(task.task_id.as_str())
+            .await
+            .map(progress_token_to_json);
+        let payload = Self::build_tool_call_payload(
+            request.name.as_ref(),
+            prepared.arguments,
+            progress_token,
+            Some(task.task_id.as_str()),
+        );
+        if let Err(err) = self.emit_event("MCP_TOOL_CALL", payload) {
+            let mut tasks = self.tasks.lock().await;
+            tasks.remove(task.task_id.as_str());
+            return Err(err);
         }
+        Ok(rmcp::model::CreateTaskResult::new(task))
     }
 
-    fn list_resources(
+    async fn list_resources(
         &self,
         _request: Option<PaginatedRequestParams>,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<ListResourcesResult, McpError>> + Send + '_ {
-        async move {
-            let resources = {
-                let guard = self
-                    .registry
-                    .read()
-                    .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
-                guard.list_resources()
-            };
-            Ok(ListResourcesResult::with_all_items(resources))
-        }
+    ) -> Result<ListResourcesResult, McpError> {
+        let resources = {
+            let guard = self
+                .registry
+                .read()
+                .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
+            guard.list_resources()
+        };
+        Ok(ListResourcesResult::with_all_items(resources))
     }
 
-    fn list_resource_templates(
+    async fn list_resource_templates(
         &self,
         _request: Option<PaginatedRequestParams>,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<ListResourceTemplatesResult, McpError>> + Send + '_
-    {
-        async move {
-            let resource_templates = {
-                let guard = self
-                    .registry
-                    .read()
-                    .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
-                guard.list_resource_templates()
-            };
-            Ok(ListResourceTemplatesResult::with_all_items(
-                resource_templates,
-            ))
-        }
+    ) -> Result<ListResourceTemplatesResult, McpError> {
+        let resource_templates = {
+            let guard = self
+                .registry
+                .read()
+                .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
+            guard.list_resource_templates()
+        };
+        Ok(ListResourceTemplatesResult::with_all_items(
+            resource_templates,
+        ))
     }
 
-    fn read_resource(
+    async fn read_resource(
         &self,
         request: ReadResourceRequestParams,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<ReadResourceResult, McpError>> + Send + '_ {
-        async move {
-            let uri = request.uri.clone();
-            let resolved = {
-                let guard = self
-                    .registry
-                    .read()
-                    .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
-                guard.resolve_resource(uri.as_str())
-            };
+    ) -> Result<ReadResourceResult, McpError> {
+        let uri = request.uri.clone();
+        let resolved = {
+            let guard = self
+                .registry
+                .read()
+                .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
+            guard.resolve_resource(uri.as_str())
+        };
 
-            let payload = match resolved {
-                Ok(Some(ResolvedResource::Resource(_resource))) => {
-                    serde_json::json!({ "uri": uri })
-                }
-                Ok(Some(ResolvedResource::Template(template))) => serde_json::json!({
-                    "uri": uri,
-                    "uriTemplate": template.template.uri_template,
-                    "arguments": template.arguments,
-                }),
-                Ok(None) => {
-                    return Err(McpError::resource_not_found("resource not found", None));
-                }
-                Err(ResolveResourceError::AmbiguousTemplates { uri, templates }) => {
-                    return Err(McpError::invalid_params(
-                        "resource URI matches multiple templates",
-                        Some(serde_json::json!({
-                            "uri": uri,
-                            "uriTemplates": templates,
-                        })),
-                    ));
-                }
-            };
+        let payload = match resolved {
+            Ok(Some(ResolvedResource::Resource(_resource))) => {
+                serde_json::json!({ "uri": uri })
+            }
+            Ok(Some(ResolvedResource::Template(template))) => serde_json::json!({
+                "uri": uri,
+                "uriTemplate": template.template.uri_template,
+                "arguments": template.arguments,
+            }),
+            Ok(None) => {
+                return Err(McpError::resource_not_found("resource not found", None));
+            }
+            Err(ResolveResourceError::AmbiguousTemplates { uri, templates }) => {
+                return Err(McpError::invalid_params(
+                    "resource URI matches multiple templates",
+                    Some(serde_json::json!({
+                        "uri": uri,
+                        "uriTemplates": templates,
+                    })),
+                ));
+            }
+        };
 
-            self.dispatch_request("MCP_RESOURCE_READ", payload).await
-        }
+        self.dispatch_request("MCP_RESOURCE_READ", payload).await
     }
 
-    fn list_prompts(
+    async fn list_prompts(
         &self,
         _request: Option<PaginatedRequestParams>,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<ListPromptsResult, McpError>> + Send + '_ {
-        async move {
-            let prompts = {
-                let guard = self
-                    .registry
-                    .read()
-                    .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
-                guard.list_prompts()
-            };
-            Ok(ListPromptsResult::with_all_items(prompts))
-        }
+    ) -> Result<ListPromptsResult, McpError> {
+        let prompts = {
+            let guard = self
+                .registry
+                .read()
+                .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
+            guard.list_prompts()
+        };
+        Ok(ListPromptsResult::with_all_items(prompts))
     }
 
-    fn get_prompt(
+    async fn get_prompt(
         &self,
         request: GetPromptRequestParams,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<GetPromptResult, McpError>> + Send + '_ {
-        async move {
-            let name = request.name.clone();
-            let exists = {
-                let guard = self
-                    .registry
-                    .read()
-                    .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
-                guard.get_prompt(name.as_str())
-            };
-            if exists.is_none() {
-                return Err(McpError::invalid_params("prompt not found", None));
-            }
-            let payload = serde_json::json!({
-                "name": name,
-                "arguments": Self::arguments_payload(request.arguments.as_ref()),
-            });
-            self.dispatch_request("MCP_PROMPT_GET", payload).await
+    ) -> Result<GetPromptResult, McpError> {
+        let name = request.name.clone();
+        let exists = {
+            let guard = self
+                .registry
+                .read()
+                .map_err(|_| McpError::internal_error("Registry lock poisoned", None))?;
+            guard.get_prompt(name.as_str())
+        };
+        if exists.is_none() {
+            return Err(McpError::invalid_params("prompt not found", None));
         }
+        let payload = serde_json::json!({
+            "name": name,
+            "arguments": Self::arguments_payload(request.arguments.as_ref()),
+        });
+        self.dispatch_request("MCP_PROMPT_GET", payload).await
     }
 
-    fn subscribe(
+    async fn subscribe(
         &self,
         request: SubscribeRequestParams,
         context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<(), McpError>> + Send + '_ {
-        async move {
-            let uri = request.uri.clone();
-            {
-                let mut subs = self.subscriptions.lock().await;
-                let entry = subs.entry(uri.clone()).or_default();
-                add_unique_sink(entry, context.peer.clone());
-            }
-            let _ = self.emit_event("MCP_RESOURCE_SUBSCRIBE", serde_json::json!({ "uri": uri }));
-            Ok(())
+    ) -> Result<(), McpError> {
+        let uri = request.uri.clone();
+        {
+            let mut subs = self.subscriptions.lock().await;
+            let entry = subs.entry(uri.clone()).or_default();
+            add_unique_sink(entry, context.peer.clone());
         }
+        let _ = self.emit_event("MCP_RESOURCE_SUBSCRIBE", serde_json::json!({ "uri": uri }));
+        Ok(())
     }
 
-    fn unsubscribe(
+    async fn unsubscribe(
         &self,
         request: UnsubscribeRequestParams,
         context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<(), McpError>> + Send + '_ {
-        async move {
-            let uri = request.uri.clone();
-            let requester_id = peer_id(&context.peer);
-            {
-                let mut subs = self.subscriptions.lock().await;
-                if let Some(entry) = subs.get_mut(&uri) {
-                    entry.retain(|s| {
-                        !s.is_transport_closed()
-                            && requester_id.map_or(true, |id| peer_id(s) != Some(id))
-                    });
-                    if entry.is_empty() {
-                        subs.remove(&uri);
-                    }
+    ) -> Result<(), McpError> {
+        let uri = request.uri.clone();
+        let requester_id = peer_id(&context.peer);
+        {
+            let mut subs = self.subscriptions.lock().await;
+            if let Some(entry) = subs.get_mut(&uri) {
+                entry.retain(|s| {
+                    !s.is_transport_closed() && requester_id.is_none_or(|id| peer_id(s) != Some(id))
+                });
+                if entry.is_empty() {
+                    subs.remove(&uri);
                 }
             }
-            let _ = self.emit_event(
-                "MCP_RESOURCE_UNSUBSCRIBE",
-                serde_json::json!({ "uri": uri }),
-            );
-            Ok(())
         }
+        let _ = self.emit_event(
+            "MCP_RESOURCE_UNSUBSCRIBE",
+            serde_json::json!({ "uri": uri }),
+        );
+        Ok(())
     }
 
-    fn on_custom_request(
+    async fn on_custom_request(
         &self,
         request: CustomRequest,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<CustomResult, McpError>> + Send + '_ {
-        async move {
-            Err(McpError::new(
-                ErrorCode::METHOD_NOT_FOUND,
-                request.method,
-                None,
-            ))
-        }
+    ) -> Result<CustomResult, McpError> {
+        Err(McpError::new(
+            ErrorCode::METHOD_NOT_FOUND,
+            request.method,
+            None,
+        ))
     }
 
-    fn on_initialized(
-        &self,
-        context: NotificationContext<RoleServer>,
-    ) -> impl std::future::Future<Output = ()> + Send + '_ {
-        async move {
-            {
-                let mut sinks = self.client_sinks.lock().await;
-                add_unique_sink(&mut sinks, context.peer.clone());
-            }
-            self.dispatch_notification("notifications/initialized", None)
-                .await
+    async fn on_initialized(&self, context: NotificationContext<RoleServer>) -> () {
+        {
+            let mut sinks = self.client_sinks.lock().await;
+            add_unique_sink(&mut sinks, context.peer.clone());
         }
+        self.dispatch_notification("notifications/initialized", None)
+            .await
     }
 
-    fn on_progress(
+    async fn on_progress(
         &self,
         notification: rmcp::model::ProgressNotificationParam,
         _context: NotificationContext<RoleServer>,
-    ) -> impl std::future::Future<Output = ()> + Send + '_ {
-        async move {
-            let params = serde_json::to_value(notification).ok();
-            self.dispatch_notification("notifications/progress", params)
-                .await
-        }
+    ) -> () {
+        let params = serde_json::to_value(notification).ok();
+        self.dispatch_notification("notifications/progress", params)
+            .await
     }
 
-    fn on_cancelled(
+    async fn on_cancelled(
         &self,
         notification: rmcp::model::CancelledNotificationParam,
         _context: NotificationContext<RoleServer>,
-    ) -> impl std::future::Future<Output = ()> + Send + '_ {
-        async move {
-            let params = serde_json::to_value(notification).ok();
-            self.dispatch_notification("notifications/cancelled", params)
-                .await
-        }
+    ) -> () {
+        let params = serde_json::to_value(notification).ok();
+        self.dispatch_notification("notifications/cancelled", params)
+            .await
     }
 
-    fn on_roots_list_changed(
-        &self,
-        _context: NotificationContext<RoleServer>,
-    ) -> impl std::future::Future<Output = ()> + Send + '_ {
-        async move {
-            self.dispatch_notification("notifications/roots/list_changed", None)
-                .await
-        }
+    async fn on_roots_list_changed(&self, _context: NotificationContext<RoleServer>) -> () {
+        self.dispatch_notification("notifications/roots/list_changed", None)
+            .await
     }
 
-    fn on_custom_notification(
+    async fn on_custom_notification(
         &self,
         notification: CustomNotification,
         _context: NotificationContext<RoleServer>,
-    ) -> impl std::future::Future<Output = ()> + Send + '_ {
-        async move {
-            self.dispatch_notification(notification.method.as_str(), notification.params)
-                .await
-        }
+    ) -> () {
+        self.dispatch_notification(notification.method.as_str(), notification.params)
+            .await
     }
 
-    fn list_tasks(
+    async fn list_tasks(
         &self,
         _request: Option<PaginatedRequestParams>,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<ListTasksResult, McpError>> + Send + '_ {
-        async move {
-            let tasks = {
-                let tasks = self.tasks.lock().await;
-                tasks
-                    .values()
-                    .map(|entry| entry.task.clone())
-                    .collect::<Vec<_>>()
-            };
-            Ok(ListTasksResult::new(tasks))
-        }
+    ) -> Result<ListTasksResult, McpError> {
+        let tasks = {
+            let tasks = self.tasks.lock().await;
+            tasks
+                .values()
+                .map(|entry| entry.task.clone())
+                .collect::<Vec<_>>()
+        };
+        Ok(ListTasksResult::new(tasks))
     }
 
-    fn get_task_info(
+    async fn get_task_info(
         &self,
         request: GetTaskInfoParams,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<GetTaskResult, McpError>> + Send + '_ {
-        async move {
-            let task = {
-                let tasks = self.tasks.lock().await;
-                tasks
-                    .get(request.task_id.as_str())
-                    .map(|entry| entry.task.clone())
-            }
-            .ok_or_else(|| McpError::invalid_params("task not found", None))?;
-            Ok(GetTaskResult { meta: None, task })
+    ) -> Result<GetTaskResult, McpError> {
+        let task = {
+            let tasks = self.tasks.lock().await;
+            tasks
+                .get(request.task_id.as_str())
+                .map(|entry| entry.task.clone())
         }
+        .ok_or_else(|| McpError::invalid_params("task not found", None))?;
+        Ok(GetTaskResult { meta: None, task })
     }
 
-    fn get_task_result(
+    async fn get_task_result(
         &self,
         request: GetTaskResultParams,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<GetTaskPayloadResult, McpError>> + Send + '_ {
-        async move {
-            let result = {
-                let tasks = self.tasks.lock().await;
-                tasks
-                    .get(request.task_id.as_str())
-                    .and_then(|entry| entry.result.clone())
-            }
-            .ok_or_else(|| McpError::invalid_params("task result is not ready", None))?;
-            match result {
-                Ok(value) => Ok(GetTaskPayloadResult::new(value)),
-                Err(error) => Err(error),
-            }
+    ) -> Result<GetTaskPayloadResult, McpError> {
+        let result = {
+            let tasks = self.tasks.lock().await;
+            tasks
+                .get(request.task_id.as_str())
+                .and_then(|entry| entry.result.clone())
+        }
+        .ok_or_else(|| McpError::invalid_params("task result is not ready", None))?;
+        match result {
+            Ok(value) => Ok(GetTaskPayloadResult::new(value)),
+            Err(error) => Err(error),
         }
     }
 
-    fn cancel_task(
+    async fn cancel_task(
         &self,
         request: CancelTaskParams,
         _context: RequestContext<RoleServer>,
-    ) -> impl std::future::Future<Output = Result<CancelTaskResult, McpError>> + Send + '_ {
-        async move {
-            let task = {
-                let mut tasks = self.tasks.lock().await;
-                let entry = tasks
-                    .get_mut(request.task_id.as_str())
-                    .ok_or_else(|| McpError::invalid_params("task not found", None))?;
-                entry.task.status = TaskStatus::Cancelled;
-                entry.task.status_message = Some("Task was cancelled".to_owned());
-                entry.task.last_updated_at = chrono::Utc::now().to_rfc3339();
-                entry.result = Some(Err(McpError::invalid_request("Task was cancelled", None)));
-                entry.task.clone()
-            };
-            let _ = self.emit_event(
-                "MCP_TASK_CANCELLED",
-                serde_json::json!({ "taskId": request.task_id }),
-            );
-            Ok(CancelTaskResult { meta: None, task })
-        }
+    ) -> Result<CancelTaskResult, McpError> {
+        let task = {
+            let mut tasks = self.tasks.lock().await;
+            let entry = tasks
+                .get_mut(request.task_id.as_str())
+                .ok_or_else(|| McpError::invalid_params("task not found", None))?;
+            entry.task.status = TaskStatus::Cancelled;
+            entry.task.status_message = Some("Task was cancelled".to_owned());
+            entry.task.last_updated_at = chrono::Utc::now().to_rfc3339();
+            entry.result = Some(Err(McpError::invalid_request("Task was cancelled", None)));
+            entry.task.clone()
+        };
+        let _ = self.emit_event(
+            "MCP_TASK_CANCELLED",
+            serde_json::json!({ "taskId": request.task_id }),
+        );
+        Ok(CancelTaskResult { meta: None, task })
     }
 }
 
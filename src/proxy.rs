@@ -0,0 +1,213 @@
+use std::error::Error;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde_json::Value;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::http::Uri;
+
+use crate::option_str;
+
+/// The proxy answer to CONNECT is a short header block; anything longer is not a proxy.
+const MAX_CONNECT_RESPONSE_BYTES: usize = 8192;
+
+/// HTTP proxy for outgoing connections, parsed from the `proxy` section of the options JSON.
+/// Both `ws://` and `wss://` connections are tunnelled with `CONNECT`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProxyOptions {
+    host: String,
+    port: u16,
+    /// Value of the `Proxy-Authorization` header.
+    authorization: Option<String>,
+    /// Host names that are reached directly, lower case, without the leading `*.`/`.`.
+    bypass: Vec<String>,
+}
+
+impl ProxyOptions {
+    pub(crate) fn from_json(value: &Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект в параметре proxy".to_owned())?;
+
+        let url = option_str(obj, "url")?.ok_or_else(|| "Не задан адрес прокси url".to_owned())?;
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|err| format!("Некорректный адрес прокси {url}: {err}"))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(format!("Поддерживается только прокси http://, получен {url}").into());
+        }
+        let host = uri
+            .host()
+            .ok_or_else(|| format!("Не указан хост прокси в адресе {url}"))?;
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_owned();
+        let port = uri.port_u16().unwrap_or(80);
+
+        let authorization = match (option_str(obj, "user")?, option_str(obj, "password")?) {
+            (Some(user), password) => {
+                let credentials = format!("{user}:{}", password.unwrap_or_default());
+                Some(format!("Basic {}", BASE64.encode(credentials)))
+            }
+            (None, None) => None,
+            (None, Some(_)) => {
+                return Err("Параметр password задаётся только вместе с user"
+                    .to_owned()
+                    .into())
+            }
+        };
+
+        let bypass = match obj.get("bypass") {
+            None | Some(Value::Null) => Vec::new(),
+            Some(Value::Array(items)) => items
+                .iter()
+                .map(|item| {
+                    item.as_str()
+                        .map(|host| {
+                            host.trim()
+                                .trim_start_matches("*.")
+                                .trim_start_matches('.')
+                                .to_ascii_lowercase()
+                        })
+                        .ok_or_else(|| "Параметр bypass должен быть массивом строк".into())
+                })
+                .collect::<Result<_, Box<dyn Error>>>()?,
+            Some(_) => return Err("Параметр bypass должен быть массивом строк".into()),
+        };
+
+        Ok(Self {
+            host,
+            port,
+            authorization,
+            bypass,
+        })
+    }
+
+    /// `*` bypasses the proxy for every host; any other entry matches the host itself
+    /// and all of its subdomains.
+    pub(crate) fn bypasses(&self, host: &str) -> bool {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        self.bypass.iter().any(|entry| {
+            entry == "*"
+                || host == *entry
+                || host
+                    .strip_suffix(entry.as_str())
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+        })
+    }
+
+    /// Opens a TCP connection to the proxy and asks it to tunnel to `host:port`.
+    pub(crate) async fn tunnel(&self, host: &str, port: u16) -> Result<TcpStream, String> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .await
+            .map_err(|err| {
+                format!(
+                    "Не удалось подключиться к прокси {}:{}: {err}",
+                    self.host, self.port
+                )
+            })?;
+
+        let target = match host.contains(':') && !host.starts_with('[') {
+            true => format!("[{host}]:{port}"),
+            false => format!("{host}:{port}"),
+        };
+        let mut request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n");
+        if let Some(authorization) = &self.authorization {
+            request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+        }
+        request.push_str("\r\n");
+        stream
+            .write_all(request.as_bytes())
+            .await
+            .map_err(|err| format!("Ошибка записи в прокси: {err}"))?;
+
+        let head = read_response_head(&mut stream).await?;
+        let status_line = head.lines().next().unwrap_or_default();
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if !status.starts_with('2') {
+            return Err(format!(
+                "Прокси отклонил подключение к {target}: {status_line}"
+            ));
+        }
+        Ok(stream)
+    }
+}
+
+/// Reads the proxy response up to the empty line, byte by byte, so that nothing
+/// the target server sends after it is consumed.
+async fn read_response_head(stream: &mut TcpStream) -> Result<String, String> {
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_CONNECT_RESPONSE_BYTES {
+            return Err("Слишком длинный ответ прокси на CONNECT".to_owned());
+        }
+        let byte = stream
+            .read_u8()
+            .await
+            .map_err(|err| format!("Прокси закрыл соединение: {err}"))?;
+        head.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&head).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxy(options: Value) -> ProxyOptions {
+        ProxyOptions::from_json(&options).unwrap()
+    }
+
+    #[test]
+    fn parses_url_and_credentials() {
+        let options = proxy(serde_json::json!({
+            "url": "http://proxy.local:3128",
+            "user": "alice",
+            "password": "secret",
+        }));
+        assert_eq!((options.host.as_str(), options.port), ("proxy.local", 3128));
+        assert_eq!(
+            options.authorization.as_deref(),
+            Some("Basic YWxpY2U6c2VjcmV0")
+        );
+
+        let options = proxy(serde_json::json!({ "url": "http://proxy.local" }));
+        assert_eq!((options.port, options.authorization), (80, None));
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        for options in [
+            serde_json::json!("http://proxy.local"),
+            serde_json::json!({}),
+            serde_json::json!({ "url": "https://proxy.local" }),
+            serde_json::json!({ "url": "http://proxy.local", "password": "secret" }),
+            serde_json::json!({ "url": "http://proxy.local", "bypass": "localhost" }),
+        ] {
+            assert!(ProxyOptions::from_json(&options).is_err(), "{options}");
+        }
+    }
+
+    #[test]
+    fn bypass_matches_hosts_and_subdomains() {
+        let options = proxy(serde_json::json!({
+            "url": "http://proxy.local",
+            "bypass": ["localhost", "*.corp.local", ".example.com", "::1"],
+        }));
+        assert!(options.bypasses("localhost"));
+        assert!(options.bypasses("LOCALHOST"));
+        assert!(options.bypasses("api.corp.local"));
+        assert!(options.bypasses("corp.local"));
+        assert!(options.bypasses("a.b.example.com"));
+        assert!(options.bypasses("[::1]"));
+        assert!(!options.bypasses("notcorp.local"));
+        assert!(!options.bypasses("example.org"));
+
+        let options = proxy(serde_json::json!({ "url": "http://proxy.local", "bypass": ["*"] }));
+        assert!(options.bypasses("anything.example"));
+    }
+}
@@ -0,0 +1,227 @@
+use std::error::Error;
+use std::sync::{Arc, RwLock};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme};
+use serde_json::Value;
+
+use crate::{option_bool, option_str};
+
+/// TLS settings of outgoing connections, parsed from the `tls` section of the options JSON.
+#[derive(Default)]
+pub(crate) struct ClientTlsOptions {
+    ca_certs: Vec<CertificateDer<'static>>,
+    client_auth: Option<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)>,
+    accept_invalid_certs: bool,
+}
+
+impl ClientTlsOptions {
+    pub(crate) fn from_json(value: &Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект в параметре tls".to_owned())?;
+
+        let ca_certs = match option_str(obj, "caCert")? {
+            Some(value) => load_certificates(value)?,
+            None => Vec::new(),
+        };
+        let client_auth = match (
+            option_str(obj, "clientCert")?,
+            option_str(obj, "clientKey")?,
+        ) {
+            (Some(cert), Some(key)) => Some((load_certificates(cert)?, load_private_key(key)?)),
+            (None, None) => None,
+            _ => {
+                return Err("Параметры clientCert и clientKey задаются только вместе"
+                    .to_owned()
+                    .into())
+            }
+        };
+        let accept_invalid_certs = option_bool(obj, "acceptInvalidCerts")?.unwrap_or(false);
+
+        Ok(Self {
+            ca_certs,
+            client_auth,
+            accept_invalid_certs,
+        })
+    }
+
+    pub(crate) fn client_config(&self) -> Result<ClientConfig, Box<dyn Error>> {
+        let provider = crypto_provider();
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+
+        let builder = if self.accept_invalid_certs {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyServerCert(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+            for cert in &self.ca_certs {
+                roots
+                    .add(cert.clone())
+                    .map_err(|err| format!("Некорректный сертификат caCert: {err}"))?;
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let config = match &self.client_auth {
+            Some((chain, key)) => builder
+                .with_client_auth_cert(chain.clone(), key.clone_key())
+                .map_err(|err| format!("Некорректный клиентский сертификат: {err}"))?,
+            None => builder.with_no_client_auth(),
+        };
+        Ok(config)
+    }
+}
+
+/// Certificate of the HTTPS listener, parsed from the `tls` section of the start options.
+pub(crate) struct ServerTlsOptions {
+    pub(crate) certified_key: Arc<CertifiedKey>,
+}
+
+impl ServerTlsOptions {
+    pub(crate) fn from_json(value: &Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект в параметре tls".to_owned())?;
+
+        match (option_str(obj, "cert")?, option_str(obj, "key")?) {
+            (Some(cert), Some(key)) => Ok(Self {
+                certified_key: load_certified_key(cert, key)?,
+            }),
+            _ => Err("Параметры cert и key обязательны".to_owned().into()),
+        }
+    }
+}
+
+/// Loads a certificate chain with its private key and checks that they belong together.
+pub(crate) fn load_certified_key(
+    cert: &str,
+    key: &str,
+) -> Result<Arc<CertifiedKey>, Box<dyn Error>> {
+    let chain = load_certificates(cert)?;
+    let key = load_private_key(key)?;
+    let certified_key = CertifiedKey::from_der(chain, key, &crypto_provider())
+        .map_err(|err| format!("Сертификат не подходит к закрытому ключу: {err}"))?;
+    Ok(Arc::new(certified_key))
+}
+
+/// Hands the current certificate to every new TLS connection, so that it can be
+/// replaced while the listener keeps running.
+#[derive(Debug)]
+pub(crate) struct ServerCertResolver(RwLock<Arc<CertifiedKey>>);
+
+impl ServerCertResolver {
+    pub(crate) fn new(certified_key: Arc<CertifiedKey>) -> Self {
+        Self(RwLock::new(certified_key))
+    }
+
+    pub(crate) fn set(&self, certified_key: Arc<CertifiedKey>) {
+        *self.0.write().unwrap() = certified_key;
+    }
+
+    pub(crate) fn server_config(self: &Arc<Self>) -> Result<ServerConfig, Box<dyn Error>> {
+        let mut config = ServerConfig::builder_with_provider(crypto_provider())
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+impl ResolvesServerCert for ServerCertResolver {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.read().unwrap().clone())
+    }
+}
+
+/// The crypto provider is chosen explicitly so that the add-in never depends on a
+/// process-wide default, which may be absent or ambiguous inside the 1C process.
+pub(crate) fn crypto_provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+pub(crate) fn load_certificates(
+    value: &str,
+) -> Result<Vec<CertificateDer<'static>>, Box<dyn Error>> {
+    let pem = read_pem(value)?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| format!("Некорректный PEM сертификата: {err}"))?;
+    if certs.is_empty() {
+        return Err("PEM не содержит сертификатов".to_owned().into());
+    }
+    Ok(certs)
+}
+
+pub(crate) fn load_private_key(value: &str) -> Result<PrivateKeyDer<'static>, Box<dyn Error>> {
+    let pem = read_pem(value)?;
+    PrivateKeyDer::from_pem_slice(&pem)
+        .map_err(|err| format!("Некорректный PEM закрытого ключа: {err}").into())
+}
+
+/// Accepts either PEM text or a path to a PEM file.
+fn read_pem(value: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    if value.contains("-----BEGIN") {
+        return Ok(value.as_bytes().to_vec());
+    }
+    std::fs::read(value).map_err(|err| format!("Не удалось прочитать файл {value}: {err}").into())
+}
+
+/// Verifier behind `acceptInvalidCerts`: skips chain and host name checks, but still
+/// verifies handshake signatures so the session keys stay bound to the presented key.
+#[derive(Debug)]
+struct AcceptAnyServerCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AcceptAnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
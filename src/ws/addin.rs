@@ -5,13 +5,14 @@ use addin1c::{name, AddinResult, CStr1C, MethodInfo, Methods, PropInfo, SimpleAd
 use tokio::runtime::Runtime;
 
 use crate::ws_client;
-use crate::ws_client::WebSocketConnection;
+use crate::ws_client::{WsConnections, WsOptions};
 use crate::{addin_error::report_platform_error, VERSION};
 
 pub struct WsAddIn {
     pub(super) connection: Option<&'static addin1c::Connection>,
     pub(super) runtime: Arc<Runtime>,
-    pub(super) websocket: Option<WebSocketConnection>,
+    pub(super) websockets: WsConnections,
+    pub(super) options: WsOptions,
     last_error: Option<Box<dyn Error>>,
 }
 
@@ -28,31 +29,136 @@ impl WsAddIn {
     ) -> AddinResult {
         ws_client::connect(
             &self.runtime,
-            &mut self.websocket,
+            self.connection,
+            &mut self.websockets,
+            &self.options,
             address,
             json_headers,
             return_value,
         )
     }
 
+    pub(super) fn set_options(
+        &mut self,
+        options: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        self.options = ws_client::parse_options(options.get_string()?.as_str())?;
+        return_value.set_bool(true);
+        Ok(())
+    }
+
     pub(super) fn send(
         &mut self,
+        handle: &mut Variant,
         message: &mut Variant,
         return_value: &mut Variant,
     ) -> AddinResult {
-        ws_client::send(&self.runtime, &mut self.websocket, message, return_value)
+        ws_client::send(
+            &self.runtime,
+            &mut self.websockets,
+            handle,
+            message,
+            return_value,
+        )
+    }
+
+    pub(super) fn send_binary(
+        &mut self,
+        handle: &mut Variant,
+        data: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        ws_client::send_binary(
+            &self.runtime,
+            &mut self.websockets,
+            handle,
+            data,
+            return_value,
+        )
     }
 
     pub(super) fn receive(
         &mut self,
+        handle: &mut Variant,
         timeout: &mut Variant,
         return_value: &mut Variant,
     ) -> AddinResult {
-        ws_client::receive(&self.runtime, &mut self.websocket, timeout, return_value)
+        ws_client::receive(
+            &self.runtime,
+            &mut self.websockets,
+            handle,
+            timeout,
+            return_value,
+        )
+    }
+
+    pub(super) fn receive_binary(
+        &mut self,
+        handle: &mut Variant,
+        timeout: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        ws_client::receive_binary(
+            &self.runtime,
+            &mut self.websockets,
+            handle,
+            timeout,
+            return_value,
+        )
+    }
+
+    pub(super) fn receive_batch(
+        &mut self,
+        handle: &mut Variant,
+        count: &mut Variant,
+        timeout: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        ws_client::receive_batch(
+            &self.runtime,
+            &mut self.websockets,
+            handle,
+            count,
+            timeout,
+            return_value,
+        )
+    }
+
+    pub(super) fn call_rpc(
+        &mut self,
+        handle: &mut Variant,
+        method: &mut Variant,
+        params: &mut Variant,
+        timeout: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        ws_client::call_rpc(
+            &self.runtime,
+            &mut self.websockets,
+            handle,
+            method,
+            params,
+            timeout,
+            return_value,
+        )
     }
 
-    pub(super) fn disconnect(&mut self, return_value: &mut Variant) -> AddinResult {
-        ws_client::disconnect(&mut self.websocket, return_value)
+    pub(super) fn disconnect(
+        &mut self,
+        handle: &mut Variant,
+        code: &mut Variant,
+        reason: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        ws_client::disconnect(
+            &self.runtime,
+            &mut self.websockets,
+            handle,
+            code,
+            reason,
+            return_value,
+        )
     }
 
     fn version(&mut self, return_value: &mut Variant) -> AddinResult {
@@ -60,6 +166,38 @@ impl WsAddIn {
         Ok(())
     }
 
+    fn last_frame_type(&mut self, handle: &mut Variant, return_value: &mut Variant) -> AddinResult {
+        ws_client::last_frame_type(&mut self.websockets, handle, return_value)
+    }
+
+    fn state(&mut self, handle: &mut Variant, return_value: &mut Variant) -> AddinResult {
+        ws_client::state(&mut self.websockets, handle, return_value)
+    }
+
+    fn close_code(&mut self, handle: &mut Variant, return_value: &mut Variant) -> AddinResult {
+        ws_client::close_code(&mut self.websockets, handle, return_value)
+    }
+
+    fn close_reason(&mut self, handle: &mut Variant, return_value: &mut Variant) -> AddinResult {
+        ws_client::close_reason(&mut self.websockets, handle, return_value)
+    }
+
+    fn queue_size(&mut self, handle: &mut Variant, return_value: &mut Variant) -> AddinResult {
+        ws_client::queue_size(&mut self.websockets, handle, return_value)
+    }
+
+    fn subprotocol(&mut self, handle: &mut Variant, return_value: &mut Variant) -> AddinResult {
+        ws_client::subprotocol(&mut self.websockets, handle, return_value)
+    }
+
+    fn handshake_response(
+        &mut self,
+        handle: &mut Variant,
+        return_value: &mut Variant,
+    ) -> AddinResult {
+        ws_client::handshake_response(&mut self.websockets, handle, return_value)
+    }
+
     fn last_error(&mut self, return_value: &mut Variant) -> AddinResult {
         match self.last_error.as_ref() {
             Some(err) => return_value
@@ -76,6 +214,7 @@ impl SimpleAddin for WsAddIn {
     }
     fn init(&mut self, interface: &'static addin1c::Connection) -> bool {
         self.connection = Some(interface);
+        interface.set_event_buffer_depth(128);
         true
     }
     fn save_error(&mut self, err: Option<Box<dyn Error>>) {
@@ -90,17 +229,65 @@ impl SimpleAddin for WsAddIn {
                 name: name!("Подключиться"),
                 method: Methods::Method2(Self::connect),
             },
+            MethodInfo {
+                name: name!("УстановитьПараметры"),
+                method: Methods::Method1(Self::set_options),
+            },
             MethodInfo {
                 name: name!("ОтправитьСообщение"),
-                method: Methods::Method1(Self::send),
+                method: Methods::Method2(Self::send),
+            },
+            MethodInfo {
+                name: name!("ОтправитьДвоичныеДанные"),
+                method: Methods::Method2(Self::send_binary),
             },
             MethodInfo {
                 name: name!("ПолучитьСообщение"),
-                method: Methods::Method1(Self::receive),
+                method: Methods::Method2(Self::receive),
+            },
+            MethodInfo {
+                name: name!("ПолучитьДвоичныеДанные"),
+                method: Methods::Method2(Self::receive_binary),
+            },
+            MethodInfo {
+                name: name!("ПолучитьСообщения"),
+                method: Methods::Method3(Self::receive_batch),
+            },
+            MethodInfo {
+                name: name!("ВызватьRPC"),
+                method: Methods::Method4(Self::call_rpc),
             },
             MethodInfo {
                 name: name!("Отключиться"),
-                method: Methods::Method0(Self::disconnect),
+                method: Methods::Method3(Self::disconnect),
+            },
+            MethodInfo {
+                name: name!("ТипСообщения"),
+                method: Methods::Method1(Self::last_frame_type),
+            },
+            MethodInfo {
+                name: name!("Состояние"),
+                method: Methods::Method1(Self::state),
+            },
+            MethodInfo {
+                name: name!("КодЗакрытия"),
+                method: Methods::Method1(Self::close_code),
+            },
+            MethodInfo {
+                name: name!("ПричинаЗакрытия"),
+                method: Methods::Method1(Self::close_reason),
+            },
+            MethodInfo {
+                name: name!("РазмерОчереди"),
+                method: Methods::Method1(Self::queue_size),
+            },
+            MethodInfo {
+                name: name!("Подпротокол"),
+                method: Methods::Method1(Self::subprotocol),
+            },
+            MethodInfo {
+                name: name!("ОтветРукопожатия"),
+                method: Methods::Method1(Self::handshake_response),
             },
             MethodInfo {
                 name: name!("Версия"),
@@ -123,7 +310,8 @@ impl Default for WsAddIn {
         Self {
             connection: None,
             last_error: None,
-            websocket: None,
+            websockets: WsConnections::default(),
+            options: WsOptions::default(),
             runtime: Arc::new(Runtime::new().unwrap()),
         }
     }
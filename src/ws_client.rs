@@ -1,29 +1,743 @@
 use addin1c::{AddinResult, Variant};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::net::TcpStream;
 use tokio::runtime::Runtime;
+use tokio::sync::{oneshot, watch, Mutex, Notify};
+use tokio::task::JoinHandle;
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector};
 use tokio_tungstenite::{
-    connect_async,
-    tungstenite::http::{Request as WsRequest, Uri},
+    client_async,
+    tungstenite::{
+        error::{TlsError, UrlError},
+        handshake::client::Response as WsResponse,
+        http::{
+            header::{SEC_WEBSOCKET_EXTENSIONS, SEC_WEBSOCKET_PROTOCOL},
+            HeaderValue, Request as WsRequest, Uri,
+        },
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Error as WsError, Message,
+    },
     MaybeTlsStream, WebSocketStream,
 };
 
+use crate::events::{connection_sink, notify, EventSink, CLOSE_HANDSHAKE_TIMEOUT_MS};
+use crate::proxy::ProxyOptions;
+use crate::tls::ClientTlsOptions;
+use crate::ws_deflate::{DeflateOptions, DeflateStream};
+use crate::{option_bool, option_f64, option_str, option_u64};
+
+/// A close frame payload is limited to 125 bytes, two of which hold the code.
+const MAX_CLOSE_REASON_BYTES: usize = 123;
+/// Error codes of the `timeouts` limits.
+const CONNECT_TIMEOUT: &str = "WS_CONNECT_TIMEOUT";
+const HANDSHAKE_TIMEOUT: &str = "WS_HANDSHAKE_TIMEOUT";
+const SEND_TIMEOUT: &str = "WS_SEND_TIMEOUT";
+
+type WsStream = WebSocketStream<DeflateStream<MaybeTlsStream<TcpStream>>>;
+type WsSink = SplitSink<WsStream, Message>;
+type JsonObject = serde_json::Map<String, serde_json::Value>;
+
+/// Value of the `Состояние` property.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum ConnectionState {
+    Connecting,
+    Open,
+    Closing,
+    Closed,
+}
+
+impl ConnectionState {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Connecting => "connecting",
+            ConnectionState::Open => "open",
+            ConnectionState::Closing => "closing",
+            ConnectionState::Closed => "closed",
+        }
+    }
+}
+
+/// State shared between the 1C-facing methods and the background reader.
+struct Shared {
+    /// Replaced by the reader after reconnecting.
+    sender: Mutex<WsSink>,
+    /// Bumped every time `sender` is replaced.
+    generation: AtomicU64,
+    state: watch::Sender<ConnectionState>,
+    /// Close frame received from the server on the current socket.
+    peer_close: std::sync::Mutex<Option<CloseFrame>>,
+    /// Set by `Отключиться`: the reader must not reconnect once the socket closes.
+    closing_locally: AtomicBool,
+    /// Server answer to the opening handshake of the current socket.
+    handshake: std::sync::Mutex<Handshake>,
+    /// JSON-RPC calls waiting for a response, by request id.
+    rpc_calls: std::sync::Mutex<HashMap<u64, oneshot::Sender<JsonObject>>>,
+    last_rpc_id: AtomicU64,
+}
+
+impl Shared {
+    fn peer_close(&self) -> (Option<u16>, String) {
+        match self.peer_close.lock().unwrap().as_ref() {
+            Some(frame) => (Some(u16::from(frame.code)), frame.reason.to_string()),
+            None => (None, String::new()),
+        }
+    }
+
+    /// Hands a JSON-RPC response to the call waiting for it. Returns the message
+    /// back when it is not a response to a pending call.
+    fn answer_rpc(&self, message: Message) -> Option<Message> {
+        let mut calls = self.rpc_calls.lock().unwrap();
+        // Parsing is skipped while no call is waiting.
+        if calls.is_empty() {
+            return Some(message);
+        }
+        let Message::Text(text) = &message else {
+            return Some(message);
+        };
+        let Ok(serde_json::Value::Object(response)) = serde_json::from_str(text) else {
+            return Some(message);
+        };
+        let is_response = response.get("jsonrpc").and_then(|v| v.as_str()) == Some("2.0")
+            && !response.contains_key("method")
+            && (response.contains_key("result") || response.contains_key("error"));
+        let call = response
+            .get("id")
+            .and_then(|id| id.as_u64())
+            .filter(|_| is_response)
+            .and_then(|id| calls.remove(&id));
+        match call {
+            Some(call) => {
+                let _ = call.send(response);
+                None
+            }
+            None => Some(message),
+        }
+    }
+}
+
+/// Status, headers and negotiated subprotocol of the handshake response.
+#[derive(Debug, Default)]
+struct Handshake {
+    status: u16,
+    headers: serde_json::Map<String, serde_json::Value>,
+    subprotocol: Option<String>,
+}
+
+impl Handshake {
+    /// Header names are lower case; a header sent several times, such as `set-cookie`,
+    /// becomes an array of its values.
+    fn from_response(response: &WsResponse) -> Self {
+        let mut headers = serde_json::Map::new();
+        for (name, value) in response.headers() {
+            let value = serde_json::Value::from(String::from_utf8_lossy(value.as_bytes()));
+            match headers.get_mut(name.as_str()) {
+                None => {
+                    headers.insert(name.as_str().to_owned(), value);
+                }
+                Some(serde_json::Value::Array(values)) => values.push(value),
+                Some(first) => *first = serde_json::Value::Array(vec![first.take(), value]),
+            }
+        }
+        let subprotocol = response
+            .headers()
+            .get(SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned);
+        Self {
+            status: response.status().as_u16(),
+            headers,
+            subprotocol,
+        }
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "status": self.status,
+            "headers": self.headers,
+            "subprotocol": self.subprotocol,
+        })
+    }
+}
+
 pub(crate) struct WebSocketConnection {
-    pub(super) sender: SplitSink<
-        WebSocketStream<MaybeTlsStream<TcpStream>>,
-        tokio_tungstenite::tungstenite::Message,
-    >,
-    pub(super) receiver: SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>,
+    shared: Arc<Shared>,
+    /// Data frames for `ПолучитьСообщение`; `None` in event mode.
+    inbound: Option<Arc<InboundQueue>>,
+    pub(super) last_frame_type: FrameType,
+    send_timeout: Option<Duration>,
+    reader: JoinHandle<()>,
+}
+
+impl WebSocketConnection {
+    fn state(&self) -> ConnectionState {
+        *self.shared.state.borrow()
+    }
+
+    fn inbound(&self) -> Result<&InboundQueue, Box<dyn Error>> {
+        self.inbound.as_deref().ok_or_else(|| {
+            "Сообщения доставляются внешними событиями WS_MESSAGE"
+                .to_owned()
+                .into()
+        })
+    }
+}
+
+/// What the reader does with a data frame that does not fit into the inbound queue.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Overflow {
+    /// Stop reading until there is room: the server is held back by TCP backpressure.
+    #[default]
+    Block,
+    DropOldest,
+    DropNewest,
+    /// Close the connection with code 1008 and without reconnecting.
+    Disconnect,
+}
+
+/// Size and overflow policy of the inbound queue in polling mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct QueueOptions {
+    capacity: usize,
+    overflow: Overflow,
+}
+
+impl Default for QueueOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 10_000,
+            overflow: Overflow::default(),
+        }
+    }
+}
+
+impl QueueOptions {
+    fn from_json(value: &serde_json::Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект в параметре queue".to_owned())?;
+        let default = Self::default();
+        let capacity = match option_u64(obj, "capacity")? {
+            None => default.capacity,
+            Some(0) => return Err("Параметр capacity должен быть больше 0".to_owned().into()),
+            Some(capacity) => capacity as usize,
+        };
+        let overflow = match option_str(obj, "overflow")? {
+            None => default.overflow,
+            Some("block") => Overflow::Block,
+            Some("dropOldest") => Overflow::DropOldest,
+            Some("dropNewest") => Overflow::DropNewest,
+            Some("disconnect") => Overflow::Disconnect,
+            Some(other) => {
+                return Err(format!(
+                    "Недопустимое значение overflow: {other}. Ожидается block, dropOldest, dropNewest или disconnect"
+                )
+                .into())
+            }
+        };
+        Ok(Self { capacity, overflow })
+    }
+}
+
+/// Bounded queue filled by the reader in polling mode and drained by
+/// `ПолучитьСообщение`/`ПолучитьСообщения`.
+struct InboundQueue {
+    state: std::sync::Mutex<QueueState>,
+    /// Signalled on every push and when the queue is closed; there is one consumer.
+    changed: Notify,
+    /// Signalled when messages are taken; the reader waits on it under `Overflow::Block`.
+    space: Notify,
+    options: QueueOptions,
+}
+
+#[derive(Default)]
+struct QueueState {
+    messages: VecDeque<Message>,
+    /// Why the connection was lost for good; returned once the messages are drained.
+    error: Option<String>,
+    /// Set when the reader has finished: nothing more will arrive.
+    closed: bool,
+}
+
+impl InboundQueue {
+    fn new(options: QueueOptions) -> Self {
+        Self {
+            state: Default::default(),
+            changed: Notify::new(),
+            space: Notify::new(),
+            options,
+        }
+    }
+
+    /// Returns `false` when the queue is full and the policy is to disconnect.
+    async fn push(&self, message: Message) -> bool {
+        if self.options.overflow == Overflow::Block {
+            loop {
+                // A notification sent between the check and the await is kept as a permit.
+                let space = self.space.notified();
+                if self.len() < self.options.capacity {
+                    break;
+                }
+                space.await;
+            }
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.messages.len() >= self.options.capacity {
+            match self.options.overflow {
+                Overflow::Block => {}
+                Overflow::DropOldest => {
+                    state.messages.pop_front();
+                }
+                Overflow::DropNewest => return true,
+                Overflow::Disconnect => return false,
+            }
+        }
+        state.messages.push_back(message);
+        drop(state);
+        self.changed.notify_one();
+        true
+    }
+
+    fn close(&self, error: Option<String>) {
+        let mut state = self.state.lock().unwrap();
+        state.error = error;
+        state.closed = true;
+        drop(state);
+        self.changed.notify_one();
+    }
+
+    fn len(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    /// Waits up to `timeout` for a message, then takes up to `max` of them. Returns an
+    /// empty list on timeout and after the connection has closed; the error that closed
+    /// the connection is returned once, after the last message.
+    async fn take(&self, max: usize, timeout: Duration) -> Result<Vec<Message>, String> {
+        let wait = async {
+            loop {
+                // A notification sent between the check and the await is kept as a permit.
+                let changed = self.changed.notified();
+                {
+                    let mut state = self.state.lock().unwrap();
+                    if !state.messages.is_empty() {
+                        let count = max.min(state.messages.len());
+                        let messages = state.messages.drain(..count).collect();
+                        self.space.notify_one();
+                        return Ok(messages);
+                    }
+                    if let Some(error) = state.error.take() {
+                        return Err(error);
+                    }
+                    if state.closed {
+                        return Ok(Vec::new());
+                    }
+                }
+                changed.await;
+            }
+        };
+        tokio::time::timeout(timeout, wait)
+            .await
+            .unwrap_or(Ok(Vec::new()))
+    }
+}
+
+impl Drop for WebSocketConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Connections of one `ws` object, keyed by the handle returned from `Подключиться`.
+/// All of them run on the object's runtime.
+#[derive(Default)]
+pub(crate) struct WsConnections {
+    connections: HashMap<i32, WebSocketConnection>,
+    last_handle: i32,
+}
+
+impl WsConnections {
+    fn get_mut(&mut self, handle: &Variant) -> Result<&mut WebSocketConnection, Box<dyn Error>> {
+        let handle = handle.get_i32()?;
+        self.connections
+            .get_mut(&handle)
+            .ok_or_else(|| format!("Соединение {handle} не найдено").into())
+    }
+
+    /// Opens a connection and returns its handle. Events of every connection carry
+    /// its handle in the `connection` field.
+    async fn open(
+        &mut self,
+        request_data: RequestData,
+        options: &WsOptions,
+        events: Option<EventSink>,
+    ) -> Result<i32, Box<dyn Error>> {
+        let handle = self.last_handle + 1;
+        let events = events.map(|emit| {
+            Arc::new(move |event: &str, mut payload: serde_json::Value| {
+                payload["connection"] = handle.into();
+                emit(event, payload)
+            }) as EventSink
+        });
+        let opened = open(request_data, options, events).await?;
+        self.last_handle = handle;
+        self.connections.insert(handle, opened);
+        Ok(handle)
+    }
 }
 
+/// Type of the last data frame returned by `ПолучитьСообщение`/`ПолучитьДвоичныеДанные`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum FrameType {
+    #[default]
+    None,
+    Text,
+    Binary,
+}
+
+impl FrameType {
+    fn of(message: Option<&Message>) -> Self {
+        match message {
+            Some(Message::Text(_)) => FrameType::Text,
+            Some(Message::Binary(_)) => FrameType::Binary,
+            _ => FrameType::None,
+        }
+    }
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            FrameType::None => "",
+            FrameType::Text => "text",
+            FrameType::Binary => "binary",
+        }
+    }
+}
+
+/// Connection options set from 1C via `УстановитьПараметры`, applied to subsequent connects.
 #[derive(Default)]
+pub(crate) struct WsOptions {
+    tls: ClientTlsOptions,
+    events: bool,
+    reconnect: Option<ReconnectPolicy>,
+    heartbeat: Option<Heartbeat>,
+    queue: QueueOptions,
+    timeouts: Timeouts,
+    subprotocols: Vec<String>,
+    proxy: Option<ProxyOptions>,
+    compression: Option<DeflateOptions>,
+}
+
+pub(crate) fn parse_options(raw: &str) -> Result<WsOptions, Box<dyn Error>> {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return Ok(WsOptions::default());
+    }
+    let value: serde_json::Value =
+        serde_json::from_str(trimmed).map_err(|e| format!("Некорректный JSON: {e}"))?;
+    let obj = value
+        .as_object()
+        .ok_or_else(|| "Ожидается JSON объект".to_owned())?;
+
+    let tls = match obj.get("tls") {
+        None | Some(serde_json::Value::Null) => ClientTlsOptions::default(),
+        Some(value) => ClientTlsOptions::from_json(value)?,
+    };
+    // Build the TLS config once so that bad certificates are reported here, not on connect.
+    tls.client_config()?;
+    let events = option_bool(obj, "events")?.unwrap_or(false);
+    let reconnect = match obj.get("reconnect") {
+        None => None,
+        Some(value) => ReconnectPolicy::from_json(value)?,
+    };
+    let heartbeat = match obj.get("heartbeat") {
+        None => None,
+        Some(value) => Heartbeat::from_json(value)?,
+    };
+    let queue = match obj.get("queue") {
+        None | Some(serde_json::Value::Null) => QueueOptions::default(),
+        Some(value) => QueueOptions::from_json(value)?,
+    };
+    let timeouts = match obj.get("timeouts") {
+        None | Some(serde_json::Value::Null) => Timeouts::default(),
+        Some(value) => Timeouts::from_json(value)?,
+    };
+    let subprotocols = match obj.get("subprotocols") {
+        None | Some(serde_json::Value::Null) => Vec::new(),
+        Some(value) => parse_subprotocols(value)?,
+    };
+    let proxy = match obj.get("proxy") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(value) => Some(ProxyOptions::from_json(value)?),
+    };
+    let compression = match obj.get("compression") {
+        None => None,
+        Some(value) => DeflateOptions::from_json(value)?,
+    };
+
+    Ok(WsOptions {
+        tls,
+        events,
+        reconnect,
+        heartbeat,
+        queue,
+        timeouts,
+        subprotocols,
+        proxy,
+        compression,
+    })
+}
+
+/// Subprotocols are offered in `Sec-WebSocket-Protocol` as a comma separated list,
+/// so each name must be an HTTP token.
+fn parse_subprotocols(value: &serde_json::Value) -> Result<Vec<String>, Box<dyn Error>> {
+    let invalid = || "Параметр subprotocols должен быть массивом строк".to_owned();
+    let items = value.as_array().ok_or_else(invalid)?;
+    items
+        .iter()
+        .map(|item| {
+            let name = item.as_str().ok_or_else(invalid)?;
+            let is_token = !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_graphic() && !"()<>@,;:\\\"/[]?={}".contains(c));
+            match is_token {
+                true => Ok(name.to_owned()),
+                false => Err(format!("Недопустимое имя подпротокола: {name:?}").into()),
+            }
+        })
+        .collect()
+}
+
+/// Exponential backoff used to re-dial a dropped connection.
+#[derive(Clone, Debug, PartialEq)]
+struct ReconnectPolicy {
+    max_attempts: u32,
+    initial_delay: Duration,
+    max_delay: Duration,
+    multiplier: f64,
+    /// Share of the delay by which each wait is randomly shifted in both directions.
+    jitter: f64,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// `true` enables the defaults, `false`/`null` disables reconnecting.
+    fn from_json(value: &serde_json::Value) -> Result<Option<Self>, Box<dyn Error>> {
+        let obj = match value {
+            serde_json::Value::Null | serde_json::Value::Bool(false) => return Ok(None),
+            serde_json::Value::Bool(true) => return Ok(Some(Self::default())),
+            serde_json::Value::Object(obj) => obj,
+            _ => {
+                return Err("Параметр reconnect должен быть булевым или JSON объектом"
+                    .to_owned()
+                    .into())
+            }
+        };
+
+        let default = Self::default();
+        let max_attempts = match option_u64(obj, "maxAttempts")? {
+            None => default.max_attempts,
+            Some(value @ 1..=0xFFFF_FFFF) => value as u32,
+            Some(_) => {
+                return Err("Параметр maxAttempts должен быть не меньше 1"
+                    .to_owned()
+                    .into())
+            }
+        };
+        let initial_delay = option_u64(obj, "initialDelayMs")?
+            .map(Duration::from_millis)
+            .unwrap_or(default.initial_delay);
+        let max_delay = option_u64(obj, "maxDelayMs")?
+            .map(Duration::from_millis)
+            .unwrap_or(default.max_delay);
+        if max_delay < initial_delay {
+            return Err("Параметр maxDelayMs не может быть меньше initialDelayMs"
+                .to_owned()
+                .into());
+        }
+        let multiplier = option_f64(obj, "multiplier")?.unwrap_or(default.multiplier);
+        if multiplier < 1.0 {
+            return Err("Параметр multiplier должен быть не меньше 1"
+                .to_owned()
+                .into());
+        }
+        let jitter = option_f64(obj, "jitter")?.unwrap_or(default.jitter);
+        if !(0.0..=1.0).contains(&jitter) {
+            return Err("Параметр jitter должен быть в диапазоне от 0 до 1"
+                .to_owned()
+                .into());
+        }
+
+        Ok(Some(Self {
+            max_attempts,
+            initial_delay,
+            max_delay,
+            multiplier,
+            jitter,
+        }))
+    }
+
+    /// Wait before the given attempt (starting at 1), capped by `max_delay` before jitter.
+    fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let base = (self.initial_delay.as_secs_f64() * self.multiplier.powi(exponent))
+            .min(self.max_delay.as_secs_f64());
+        let shift = base * self.jitter * (2.0 * random_unit() - 1.0);
+        Duration::from_secs_f64((base + shift).max(0.0))
+    }
+}
+
+/// Random number in `[0, 1]`; jitter does not need more than the std hasher's random keys.
+fn random_unit() -> f64 {
+    let value = std::collections::hash_map::RandomState::new().hash_one(std::time::Instant::now());
+    value as f64 / u64::MAX as f64
+}
+
+/// Keepalive: a Ping every `interval`, the connection is considered dead when
+/// no Pong arrives within `pong_timeout`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Heartbeat {
+    interval: Duration,
+    pong_timeout: Duration,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            pong_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Heartbeat {
+    /// `true` enables the defaults, `false`/`null` disables pings.
+    fn from_json(value: &serde_json::Value) -> Result<Option<Self>, Box<dyn Error>> {
+        let obj = match value {
+            serde_json::Value::Null | serde_json::Value::Bool(false) => return Ok(None),
+            serde_json::Value::Bool(true) => return Ok(Some(Self::default())),
+            serde_json::Value::Object(obj) => obj,
+            _ => {
+                return Err("Параметр heartbeat должен быть булевым или JSON объектом"
+                    .to_owned()
+                    .into())
+            }
+        };
+
+        let default = Self::default();
+        let interval = option_u64(obj, "intervalMs")?
+            .map(Duration::from_millis)
+            .unwrap_or(default.interval);
+        let pong_timeout = option_u64(obj, "pongTimeoutMs")?
+            .map(Duration::from_millis)
+            .unwrap_or(default.pong_timeout);
+        if interval.is_zero() || pong_timeout.is_zero() {
+            return Err("Параметры intervalMs и pongTimeoutMs должны быть больше 0"
+                .to_owned()
+                .into());
+        }
+
+        Ok(Some(Self {
+            interval,
+            pong_timeout,
+        }))
+    }
+
+    fn timer(&self) -> Interval {
+        let mut timer = tokio::time::interval_at(Instant::now() + self.interval, self.interval);
+        timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        timer
+    }
+}
+
+/// Limits on the steps of connecting and on sending; `None` waits without limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Timeouts {
+    /// TCP connection, including the CONNECT exchange with a proxy.
+    connect: Option<Duration>,
+    /// TLS and WebSocket handshakes.
+    handshake: Option<Duration>,
+    send: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: Some(Duration::from_secs(30)),
+            handshake: Some(Duration::from_secs(30)),
+            send: None,
+        }
+    }
+}
+
+impl Timeouts {
+    /// Omitted keys keep their defaults, `0` removes the limit.
+    fn from_json(value: &serde_json::Value) -> Result<Self, Box<dyn Error>> {
+        let obj = value
+            .as_object()
+            .ok_or_else(|| "Ожидается JSON объект в параметре timeouts".to_owned())?;
+        let limit = |key: &str, default: Option<Duration>| -> Result<_, Box<dyn Error>> {
+            Ok(match option_u64(obj, key)? {
+                None => default,
+                Some(0) => None,
+                Some(ms) => Some(Duration::from_millis(ms)),
+            })
+        };
+        let default = Self::default();
+        Ok(Self {
+            connect: limit("connectMs", default.connect)?,
+            handshake: limit("handshakeMs", default.handshake)?,
+            send: limit("sendMs", default.send)?,
+        })
+    }
+}
+
+/// Runs `future` within `limit`. On expiry fails with an error that starts with `code`,
+/// so that 1C code can tell the timeouts apart.
+async fn within<T, E: From<String>>(
+    limit: Option<Duration>,
+    code: &str,
+    future: impl std::future::Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let Some(limit) = limit else {
+        return future.await;
+    };
+    match tokio::time::timeout(limit, future).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("{code}: превышено время ожидания {} мс", limit.as_millis()).into()),
+    }
+}
+
+#[derive(Clone, Default)]
 struct RequestData {
     address: String,
     headers: HashMap<String, String>,
+    subprotocols: Vec<String>,
 }
 
 impl RequestData {
@@ -48,7 +762,11 @@ impl RequestData {
             HashMap::default()
         };
 
-        Ok(RequestData { address, headers })
+        Ok(RequestData {
+            address,
+            headers,
+            subprotocols: Vec::new(),
+        })
     }
 }
 
@@ -78,91 +796,2355 @@ impl TryFrom<RequestData> for WsRequest<()> {
         for (key, value) in data.headers.iter() {
             request_builder = request_builder.header(key.as_str(), value.as_str());
         }
+        if !data.subprotocols.is_empty() {
+            request_builder =
+                request_builder.header(SEC_WEBSOCKET_PROTOCOL, data.subprotocols.join(", "));
+        }
         request_builder.body(()).map_err(|error| error.to_string())
     }
 }
 
+/// Everything needed to open the same connection again: the original URL and headers,
+/// the TLS, proxy and compression settings and the reconnect policy in effect when `Подключиться` was called.
+struct Dialer {
+    request: RequestData,
+    tls: Arc<rustls::ClientConfig>,
+    proxy: Option<ProxyOptions>,
+    compression: Option<DeflateOptions>,
+    timeouts: Timeouts,
+    reconnect: Option<ReconnectPolicy>,
+}
+
+impl Dialer {
+    async fn dial(&self) -> Result<(WsStream, Handshake), String> {
+        let mut request = WsRequest::try_from(self.request.clone())?;
+        if let Some(compression) = &self.compression {
+            let offer =
+                HeaderValue::try_from(compression.offer()).map_err(|err| err.to_string())?;
+            request
+                .headers_mut()
+                .insert(SEC_WEBSOCKET_EXTENSIONS, offer);
+        }
+        let (socket, tls) = within(
+            self.timeouts.connect,
+            CONNECT_TIMEOUT,
+            self.socket(request.uri()),
+        )
+        .await?;
+        let handshake = async {
+            let socket = match tls {
+                Some(domain) => self.secure(socket, domain).await?,
+                None => MaybeTlsStream::Plain(socket),
+            };
+            let stream = DeflateStream::new(socket, self.compression.clone());
+            client_async(request, stream)
+                .await
+                .map_err(|error| format!("{error}"))
+        };
+        let (stream, response) =
+            within(self.timeouts.handshake, HANDSHAKE_TIMEOUT, handshake).await?;
+        Ok((stream, Handshake::from_response(&response)))
+    }
+
+    /// Opens the TCP connection, directly or through the proxy. Also returns the
+    /// server name to verify for `wss://`.
+    async fn socket(&self, uri: &Uri) -> Result<(TcpStream, Option<ServerName<'static>>), String> {
+        let host = uri.host().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let (tls, default_port) = match uri.scheme_str() {
+            Some("ws") => (false, 80),
+            Some("wss") => (true, 443),
+            _ => return Err(WsError::Url(UrlError::UnsupportedUrlScheme).to_string()),
+        };
+        let port = uri.port_u16().unwrap_or(default_port);
+        let domain = match tls {
+            true => Some(
+                ServerName::try_from(host.to_owned())
+                    .map_err(|_| WsError::Tls(TlsError::InvalidDnsName).to_string())?,
+            ),
+            false => None,
+        };
+
+        let socket = match &self.proxy {
+            Some(proxy) if !proxy.bypasses(host) => proxy.tunnel(host, port).await?,
+            _ => TcpStream::connect((host, port))
+                .await
+                .map_err(|error| WsError::Io(error).to_string())?,
+        };
+        Ok((socket, domain))
+    }
+
+    async fn secure(
+        &self,
+        socket: TcpStream,
+        domain: ServerName<'static>,
+    ) -> Result<MaybeTlsStream<TcpStream>, String> {
+        let stream = TlsConnector::from(self.tls.clone())
+            .connect(domain, socket)
+            .await
+            .map_err(|error| WsError::Io(error).to_string())?;
+        Ok(MaybeTlsStream::Rustls(stream))
+    }
+
+    /// Runs the reconnect policy, raising `WS_RECONNECTING` before every attempt.
+    /// Returns the new stream, its handshake and the attempt number, or `None` when
+    /// reconnecting is disabled or every attempt failed.
+    async fn redial(
+        &self,
+        events: Option<&EventSink>,
+        wait_for_queue: bool,
+    ) -> Option<(WsStream, Handshake, u32)> {
+        let policy = self.reconnect.as_ref()?;
+        let mut last_error = String::new();
+        for attempt in 1..=policy.max_attempts {
+            let delay = policy.delay(attempt);
+            let payload = serde_json::json!({
+                "attempt": attempt,
+                "maxAttempts": policy.max_attempts,
+                "delayMs": delay.as_millis() as u64,
+                "error": last_error,
+            });
+            notify(events, "WS_RECONNECTING", payload, wait_for_queue).await;
+            tokio::time::sleep(delay).await;
+            match self.dial().await {
+                Ok((stream, handshake)) => return Some((stream, handshake, attempt)),
+                Err(error) => last_error = error,
+            }
+        }
+        None
+    }
+}
+
 pub(crate) fn connect(
     runtime: &Arc<Runtime>,
-    websocket: &mut Option<WebSocketConnection>,
+    connection: Option<&'static addin1c::Connection>,
+    websockets: &mut WsConnections,
+    options: &WsOptions,
     address: &mut Variant,
     json_headers: &mut Variant,
     return_value: &mut Variant,
 ) -> AddinResult {
     runtime.clone().block_on(async {
         let request_data = RequestData::try_new(address.get_string()?, json_headers.get_string()?)?;
-        let request = WsRequest::try_from(request_data)?;
-        let (stream, _) = connect_async(request)
-            .await
-            .map_err(|error| format!("{error}"))?;
-        let (sender, receiver) = stream.split();
-        *websocket = Some(WebSocketConnection { sender, receiver });
-        return_value.set_bool(true);
+        let events = connection_sink(connection);
+        if options.events && events.is_none() {
+            return Err("Внешние события недоступны".to_owned().into());
+        }
+        let handle = websockets.open(request_data, options, events).await?;
+        return_value.set_i32(handle);
         Ok(())
     })
 }
 
+/// Dials the server and starts the background reader, which answers pings, sends
+/// heartbeats, reconnects and delivers data frames either as events or to `inbound`.
+async fn open(
+    request_data: RequestData,
+    options: &WsOptions,
+    events: Option<EventSink>,
+) -> Result<WebSocketConnection, Box<dyn Error>> {
+    let dialer = Dialer {
+        request: RequestData {
+            subprotocols: options.subprotocols.clone(),
+            ..request_data
+        },
+        tls: Arc::new(options.tls.client_config()?),
+        proxy: options.proxy.clone(),
+        compression: options.compression.clone(),
+        timeouts: options.timeouts,
+        reconnect: options.reconnect.clone(),
+    };
+    let (stream, handshake) = dialer.dial().await?;
+    let (sender, receiver) = stream.split();
+    let shared = Arc::new(Shared {
+        sender: Mutex::new(sender),
+        generation: AtomicU64::new(0),
+        state: watch::Sender::new(ConnectionState::Open),
+        peer_close: Default::default(),
+        closing_locally: AtomicBool::new(false),
+        handshake: std::sync::Mutex::new(handshake),
+        rpc_calls: Default::default(),
+        last_rpc_id: AtomicU64::new(0),
+    });
+
+    let inbound = match options.events {
+        true => None,
+        false => Some(Arc::new(InboundQueue::new(options.queue))),
+    };
+    let reader = Reader {
+        shared: shared.clone(),
+        dialer,
+        heartbeat: options.heartbeat,
+        events,
+        inbound: inbound.clone(),
+    };
+
+    Ok(WebSocketConnection {
+        shared,
+        inbound,
+        last_frame_type: FrameType::None,
+        send_timeout: options.timeouts.send,
+        reader: tokio::spawn(reader.run(receiver)),
+    })
+}
+
+/// Background task that owns the read half of the connection.
+struct Reader {
+    shared: Arc<Shared>,
+    dialer: Dialer,
+    heartbeat: Option<Heartbeat>,
+    /// Sink for 1C events; also set in polling mode, for the reconnect events.
+    events: Option<EventSink>,
+    /// `None` in event mode, where data frames go to `events` as `WS_MESSAGE`.
+    inbound: Option<Arc<InboundQueue>>,
+}
+
+impl Reader {
+    async fn run(self, mut receiver: SplitStream<WsStream>) {
+        // In polling mode 1C may never process events, so they must not hold up the reader.
+        let wait_for_queue = self.inbound.is_none();
+        loop {
+            let error = self.read(&mut receiver).await;
+            // Responses to calls made on this socket will never arrive.
+            self.shared.rpc_calls.lock().unwrap().clear();
+            // 1C is blocked in `Отключиться` until the close handshake completes.
+            let closing_locally = self.shared.closing_locally.load(Ordering::SeqCst);
+            if let (Some(error), None, false) = (&error, &self.inbound, closing_locally) {
+                let payload = serde_json::json!({ "error": error });
+                notify(self.events.as_ref(), "WS_ERROR", payload, true).await;
+            }
+
+            let redialed = match closing_locally {
+                true => None,
+                false => {
+                    if self.dialer.reconnect.is_some() {
+                        self.shared.state.send_replace(ConnectionState::Connecting);
+                    }
+                    self.dialer
+                        .redial(self.events.as_ref(), wait_for_queue)
+                        .await
+                }
+            };
+            let Some((stream, handshake, attempt)) = redialed else {
+                self.shared.state.send_replace(ConnectionState::Closed);
+                self.close(error, wait_for_queue && !closing_locally).await;
+                return;
+            };
+
+            // Swap the sink before announcing the new connection, so that subscriptions
+            // resent from the WS_RECONNECTED handler go to the new socket.
+            let (sender, new_receiver) = stream.split();
+            *self.shared.sender.lock().await = sender;
+            *self.shared.peer_close.lock().unwrap() = None;
+            *self.shared.handshake.lock().unwrap() = handshake;
+            self.shared.generation.fetch_add(1, Ordering::SeqCst);
+            self.shared.state.send_replace(ConnectionState::Open);
+            receiver = new_receiver;
+            let payload = serde_json::json!({ "attempt": attempt });
+            notify(
+                self.events.as_ref(),
+                "WS_RECONNECTED",
+                payload,
+                wait_for_queue,
+            )
+            .await;
+        }
+    }
+
+    /// Reads one socket until it is closed, fails or stops answering pings;
+    /// returns the error that ended it, if any.
+    async fn read(&self, receiver: &mut SplitStream<WsStream>) -> Option<String> {
+        let mut timer = self.heartbeat.as_ref().map(Heartbeat::timer);
+        let mut pong_deadline: Option<Instant> = None;
+        loop {
+            tokio::select! {
+                result = receiver.next() => match result {
+                    None => return None,
+                    Some(Err(error)) => return Some(error.to_string()),
+                    Some(Ok(Message::Close(frame))) => {
+                        self.shared.state.send_replace(ConnectionState::Closing);
+                        *self.shared.peer_close.lock().unwrap() = frame;
+                    }
+                    Some(Ok(Message::Pong(_))) => pong_deadline = None,
+                    // Pings are answered by tungstenite on the next read.
+                    Some(Ok(Message::Ping(_) | Message::Frame(_))) => {}
+                    Some(Ok(message)) => {
+                        if !self.deliver(message).await {
+                            return Some(self.overflow().await);
+                        }
+                    }
+                },
+                _ = tick(&mut timer) => {
+                    if let (None, Some(heartbeat)) = (pong_deadline, &self.heartbeat) {
+                        pong_deadline = Some(Instant::now() + heartbeat.pong_timeout);
+                    }
+                    // A send from 1C may hold the sink while the peer is not reading; waiting
+                    // for it would stop this loop, so the ping is skipped and the pong deadline
+                    // decides whether the peer is still alive.
+                    let Ok(mut sender) = self.shared.sender.try_lock() else {
+                        continue;
+                    };
+                    let ping = sender.send(Message::Ping(Default::default()));
+                    match tokio::time::timeout_at(pong_deadline.unwrap_or_else(Instant::now), ping).await {
+                        Ok(Ok(())) => {}
+                        Ok(Err(error)) => return Some(error.to_string()),
+                        Err(_) => return Some(self.pong_timeout_error()),
+                    }
+                }
+                _ = sleep_until(pong_deadline) => return Some(self.pong_timeout_error()),
+            }
+        }
+    }
+
+    fn pong_timeout_error(&self) -> String {
+        let timeout = self
+            .heartbeat
+            .map(|heartbeat| heartbeat.pong_timeout)
+            .unwrap_or_default();
+        format!(
+            "Сервер не ответил на ping в течение {} мс",
+            timeout.as_millis()
+        )
+    }
+
+    /// Returns `false` when the message did not fit and the connection must be closed.
+    async fn deliver(&self, message: Message) -> bool {
+        let Some(message) = self.shared.answer_rpc(message) else {
+            return true;
+        };
+        match &self.inbound {
+            Some(inbound) => inbound.push(message).await,
+            None => {
+                let payload = message_payload(message);
+                notify(self.events.as_ref(), "WS_MESSAGE", payload, true).await;
+                true
+            }
+        }
+    }
+
+    /// Applies the `disconnect` overflow policy: closes the socket without reconnecting.
+    async fn overflow(&self) -> String {
+        self.shared.closing_locally.store(true, Ordering::SeqCst);
+        let frame = CloseFrame {
+            code: CloseCode::Policy,
+            reason: "Inbound queue overflow".into(),
+        };
+        let _ = self
+            .shared
+            .sender
+            .lock()
+            .await
+            .send(Message::Close(Some(frame)))
+            .await;
+        let capacity = self
+            .inbound
+            .as_ref()
+            .map(|inbound| inbound.options.capacity);
+        format!(
+            "Очередь входящих сообщений переполнена ({} сообщений)",
+            capacity.unwrap_or_default()
+        )
+    }
+
+    /// The connection is gone for good: report the last error in polling mode, `WS_CLOSE` in event mode.
+    async fn close(&self, error: Option<String>, wait_for_queue: bool) {
+        match &self.inbound {
+            Some(inbound) => inbound.close(error),
+            None => {
+                let (code, reason) = self.shared.peer_close();
+                let payload = serde_json::json!({ "code": code, "reason": reason });
+                notify(self.events.as_ref(), "WS_CLOSE", payload, wait_for_queue).await;
+            }
+        }
+    }
+}
+
+async fn tick(timer: &mut Option<Interval>) {
+    match timer {
+        Some(timer) => {
+            timer.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn message_payload(message: Message) -> serde_json::Value {
+    let frame_type = FrameType::of(Some(&message));
+    let data = match message {
+        Message::Binary(data) => BASE64.encode(data),
+        message => message
+            .into_text()
+            .map(|text| text.to_string())
+            .unwrap_or_default(),
+    };
+    serde_json::json!({
+        "type": frame_type.as_str(),
+        "data": data,
+    })
+}
+
 pub(crate) fn send(
     runtime: &Arc<Runtime>,
-    websocket: &mut Option<WebSocketConnection>,
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
     message: &mut Variant,
     return_value: &mut Variant,
 ) -> AddinResult {
     runtime.clone().block_on(async {
         let message = message.get_string()?;
-        match websocket.as_mut() {
-            None => Err("Отсутствует установленное соединение!".to_owned().into()),
-            Some(websocket) => {
-                websocket
-                    .sender
-                    .send(tokio_tungstenite::tungstenite::Message::Text(
-                        message.into(),
-                    ))
-                    .await?;
-                return_value.set_bool(true);
-                Ok(())
-            }
-        }
+        let websocket = websockets.get_mut(handle)?;
+        send_message(websocket, Message::Text(message.into())).await?;
+        return_value.set_bool(true);
+        Ok(())
     })
 }
 
-pub(crate) fn receive(
+pub(crate) fn send_binary(
     runtime: &Arc<Runtime>,
-    websocket: &mut Option<WebSocketConnection>,
-    timeout: &mut Variant,
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    data: &mut Variant,
     return_value: &mut Variant,
 ) -> AddinResult {
     runtime.clone().block_on(async {
-        match websocket.as_mut() {
-            None => Err("Отсутствует установленное соединение!".to_owned().into()),
-            Some(websocket) => {
-                let timeout = timeout.get_i32()?;
-                match tokio::time::timeout(
-                    Duration::from_millis(timeout as u64),
-                    websocket.receiver.next(),
-                )
-                .await
-                {
-                    Err(_) | Ok(None) => {
-                        return_value.set_str1c("")?;
-                        Ok(())
-                    }
-                    Ok(Some(result)) => {
-                        let message = result?.to_text()?.to_owned();
-                        return_value.set_str1c(message)?;
-                        Ok(())
-                    }
-                }
-            }
+        let data = data
+            .get_blob()
+            .map_err(|_| "Ожидаются двоичные данные".to_owned())?
+            .to_vec();
+        let websocket = websockets.get_mut(handle)?;
+        send_message(websocket, Message::Binary(data.into())).await?;
+        return_value.set_bool(true);
+        Ok(())
+    })
+}
+
+/// Sends within the send timeout, which also covers waiting for a reconnect.
+async fn send_message(
+    websocket: &WebSocketConnection,
+    message: Message,
+) -> Result<(), Box<dyn Error>> {
+    within(
+        websocket.send_timeout,
+        SEND_TIMEOUT,
+        send_or_resend(websocket, message),
+    )
+    .await
+}
+
+/// Waits out a reconnect in progress. A send that fails on a dropped socket is retried
+/// once after the reader has reconnected, if the reconnect policy allows it.
+async fn send_or_resend(
+    websocket: &WebSocketConnection,
+    message: Message,
+) -> Result<(), Box<dyn Error>> {
+    let shared = &websocket.shared;
+    let mut state = shared.state.subscribe();
+    if *state
+        .wait_for(|state| *state != ConnectionState::Connecting)
+        .await?
+        == ConnectionState::Closed
+    {
+        return Err("Соединение закрыто".to_owned().into());
+    }
+
+    let (result, generation) = {
+        let mut sender = shared.sender.lock().await;
+        let generation = shared.generation.load(Ordering::SeqCst);
+        (sender.send(message.clone()).await, generation)
+    };
+    let Err(error) = result else {
+        return Ok(());
+    };
+    if !matches!(
+        error,
+        WsError::Io(_) | WsError::ConnectionClosed | WsError::AlreadyClosed
+    ) {
+        return Err(error.into());
+    }
+
+    // The reader sees the same socket fail and either reconnects or gives up.
+    let reconnected = *state
+        .wait_for(|state| {
+            *state == ConnectionState::Closed
+                || (*state == ConnectionState::Open
+                    && shared.generation.load(Ordering::SeqCst) != generation)
+        })
+        .await?
+        == ConnectionState::Open;
+    if !reconnected {
+        return Err(error.into());
+    }
+    shared.sender.lock().await.send(message).await?;
+    Ok(())
+}
+
+pub(crate) fn receive(
+    runtime: &Arc<Runtime>,
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    timeout: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    runtime.clone().block_on(async {
+        let websocket = websockets.get_mut(handle)?;
+        let timeout = timeout.get_i32()?;
+        let message = next_message(websocket, timeout).await?;
+        websocket.last_frame_type = FrameType::of(message.as_ref());
+        match message {
+            None => return_value.set_str1c("")?,
+            Some(Message::Binary(data)) => return_value.set_str1c(BASE64.encode(data))?,
+            Some(message) => return_value.set_str1c(message.to_text()?.to_owned())?,
         }
+        Ok(())
+    })
+}
+
+pub(crate) fn receive_binary(
+    runtime: &Arc<Runtime>,
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    timeout: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    runtime.clone().block_on(async {
+        let websocket = websockets.get_mut(handle)?;
+        let timeout = timeout.get_i32()?;
+        let message = next_message(websocket, timeout).await?;
+        websocket.last_frame_type = FrameType::of(message.as_ref());
+        match message {
+            None => return_value.set_empty(),
+            Some(message) => return_value.set_blob(&message.into_data())?,
+        }
+        Ok(())
+    })
+}
+
+/// Returns the next data frame, or `None` on timeout and after the connection has closed.
+async fn next_message(
+    websocket: &mut WebSocketConnection,
+    timeout: i32,
+) -> Result<Option<Message>, Box<dyn Error>> {
+    let timeout = Duration::from_millis(timeout as u64);
+    let mut messages = websocket.inbound()?.take(1, timeout).await?;
+    Ok(messages.pop())
+}
+
+/// Waits up to `Таймаут` for the first message and returns up to `Количество` queued
+/// messages as a JSON array of `{type, data}` objects, like `WS_MESSAGE` payloads.
+pub(crate) fn receive_batch(
+    runtime: &Arc<Runtime>,
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    count: &mut Variant,
+    timeout: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    runtime.clone().block_on(async {
+        let websocket = websockets.get_mut(handle)?;
+        let count = count.get_i32()?;
+        if count <= 0 {
+            return Err("Количество должно быть больше 0".to_owned().into());
+        }
+        let timeout = Duration::from_millis(timeout.get_i32()? as u64);
+        let messages = websocket.inbound()?.take(count as usize, timeout).await?;
+        websocket.last_frame_type = FrameType::of(messages.last());
+        let messages = messages.into_iter().map(message_payload).collect();
+        return_value.set_str1c(serde_json::Value::Array(messages).to_string())?;
+        Ok(())
+    })
+}
+
+pub(crate) fn queue_size(
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    let websocket = websockets.get_mut(handle)?;
+    return_value.set_i32(websocket.inbound()?.len() as i32);
+    Ok(())
+}
+
+/// Sends a JSON-RPC 2.0 request and waits for the response with the same id.
+/// Other messages received meanwhile are delivered as usual.
+pub(crate) fn call_rpc(
+    runtime: &Arc<Runtime>,
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    method: &mut Variant,
+    params: &mut Variant,
+    timeout: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    runtime.clone().block_on(async {
+        let websocket = websockets.get_mut(handle)?;
+        let method = method.get_string()?;
+        let params = match params.get_empty() {
+            Ok(()) => String::new(),
+            Err(_) => params.get_string()?,
+        };
+        let params = match params.trim() {
+            "" => None,
+            params => match serde_json::from_str(params) {
+                Ok(params @ (serde_json::Value::Array(_) | serde_json::Value::Object(_))) => {
+                    Some(params)
+                }
+                _ => {
+                    return Err("Параметры должны быть JSON массивом или объектом"
+                        .to_owned()
+                        .into())
+                }
+            },
+        };
+        let timeout = Duration::from_millis(timeout.get_i32()? as u64);
+        let result = rpc(websocket, &method, params, timeout).await?;
+        return_value.set_str1c(result.to_string())?;
+        Ok(())
     })
 }
 
+async fn rpc(
+    websocket: &WebSocketConnection,
+    method: &str,
+    params: Option<serde_json::Value>,
+    timeout: Duration,
+) -> Result<serde_json::Value, Box<dyn Error>> {
+    let shared = &websocket.shared;
+    let id = shared.last_rpc_id.fetch_add(1, Ordering::SeqCst) + 1;
+    let mut request = serde_json::json!({ "jsonrpc": "2.0", "id": id, "method": method });
+    if let Some(params) = params {
+        request["params"] = params;
+    }
+
+    let (tx, rx) = oneshot::channel();
+    shared.rpc_calls.lock().unwrap().insert(id, tx);
+    let response = async {
+        send_message(websocket, Message::Text(request.to_string().into())).await?;
+        rx.await.map_err(|_| -> Box<dyn Error> {
+            format!("Соединение потеряно до получения ответа на {method}").into()
+        })
+    };
+    let response = tokio::time::timeout(timeout, response).await;
+    // Nothing to remove once the reader has answered the call.
+    shared.rpc_calls.lock().unwrap().remove(&id);
+    let mut response = response.map_err(|_| {
+        format!(
+            "Нет ответа на {method} в течение {} мс",
+            timeout.as_millis()
+        )
+    })??;
+
+    match response.remove("error") {
+        Some(error) => {
+            let code = error.get("code").cloned().unwrap_or_default();
+            let message = error
+                .get("message")
+                .and_then(|m| m.as_str())
+                .unwrap_or_default();
+            let mut description = format!("JSON-RPC ошибка {code}: {message}");
+            if let Some(data) = error.get("data") {
+                description.push_str(&format!(" ({data})"));
+            }
+            Err(description.into())
+        }
+        None => Ok(response.remove("result").unwrap_or_default()),
+    }
+}
+
+pub(crate) fn last_frame_type(
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    let websocket = websockets.get_mut(handle)?;
+    return_value.set_str1c(websocket.last_frame_type.as_str())?;
+    Ok(())
+}
+
+pub(crate) fn state(
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    let websocket = websockets.get_mut(handle)?;
+    return_value.set_str1c(websocket.state().as_str())?;
+    Ok(())
+}
+
+pub(crate) fn close_code(
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    let websocket = websockets.get_mut(handle)?;
+    match websocket.shared.peer_close().0 {
+        Some(code) => return_value.set_i32(code as i32),
+        None => return_value.set_empty(),
+    }
+    Ok(())
+}
+
+pub(crate) fn close_reason(
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    let websocket = websockets.get_mut(handle)?;
+    return_value.set_str1c(websocket.shared.peer_close().1)?;
+    Ok(())
+}
+
+pub(crate) fn subprotocol(
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    let websocket = websockets.get_mut(handle)?;
+    let handshake = websocket.shared.handshake.lock().unwrap();
+    return_value.set_str1c(handshake.subprotocol.clone().unwrap_or_default())?;
+    Ok(())
+}
+
+pub(crate) fn handshake_response(
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    return_value: &mut Variant,
+) -> AddinResult {
+    let websocket = websockets.get_mut(handle)?;
+    let handshake = websocket.shared.handshake.lock().unwrap().to_json();
+    return_value.set_str1c(handshake.to_string())?;
+    Ok(())
+}
+
+/// Performs the close handshake and removes the connection from the table.
 pub(crate) fn disconnect(
-    websocket: &mut Option<WebSocketConnection>,
+    runtime: &Arc<Runtime>,
+    websockets: &mut WsConnections,
+    handle: &mut Variant,
+    code: &mut Variant,
+    reason: &mut Variant,
     return_value: &mut Variant,
 ) -> AddinResult {
-    *websocket = None;
-    return_value.set_bool(true);
+    runtime.clone().block_on(async {
+        let code = match code.get_empty() {
+            Ok(()) => u16::from(CloseCode::Normal) as i32,
+            Err(_) => code.get_i32()?,
+        };
+        let reason = match reason.get_empty() {
+            Ok(()) => String::new(),
+            Err(_) => reason.get_string()?,
+        };
+        let websocket = websockets.get_mut(handle)?;
+        close(websocket, code, reason).await?;
+        websockets.connections.remove(&handle.get_i32()?);
+        return_value.set_bool(true);
+        Ok(())
+    })
+}
+
+async fn close(
+    websocket: &mut WebSocketConnection,
+    code: i32,
+    reason: String,
+) -> Result<(), Box<dyn Error>> {
+    if !matches!(code, 1000..=1003 | 1007..=1011 | 3000..=4999) {
+        return Err(format!("Недопустимый код закрытия: {code}").into());
+    }
+    if reason.len() > MAX_CLOSE_REASON_BYTES {
+        return Err(
+            format!("Причина закрытия длиннее {MAX_CLOSE_REASON_BYTES} байт в UTF-8").into(),
+        );
+    }
+
+    let shared = &websocket.shared;
+    shared.closing_locally.store(true, Ordering::SeqCst);
+    // Nothing to close while closed or between reconnect attempts.
+    let state = *shared.state.borrow();
+    if matches!(state, ConnectionState::Open | ConnectionState::Closing) {
+        shared.state.send_replace(ConnectionState::Closing);
+        let frame = CloseFrame {
+            code: CloseCode::from(code as u16),
+            reason: reason.into(),
+        };
+        // A failed send means the socket is already gone, which is what we want anyway.
+        let _ = shared
+            .sender
+            .lock()
+            .await
+            .send(Message::Close(Some(frame)))
+            .await;
+        // The reader finishes once the server answers and closes the socket.
+        let handshake = Duration::from_millis(CLOSE_HANDSHAKE_TIMEOUT_MS);
+        let _ = tokio::time::timeout(handshake, &mut websocket.reader).await;
+    }
+    websocket.reader.abort();
+    websocket.shared.state.send_replace(ConnectionState::Closed);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use rustls::server::WebPkiClientVerifier;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+
+    // ── helpers ──────────────────────────────────────────────────────────────
+
+    struct TestPki {
+        ca_pem: String,
+        server_cert_pem: String,
+        server_key_pem: String,
+        client_cert_pem: String,
+        client_key_pem: String,
+    }
+
+    fn make_test_pki() -> TestPki {
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+
+        let server_key = KeyPair::generate().unwrap();
+        let server_cert = CertificateParams::new(vec!["localhost".to_owned()])
+            .unwrap()
+            .signed_by(&server_key, &ca)
+            .unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let client_cert = CertificateParams::new(vec!["client".to_owned()])
+            .unwrap()
+            .signed_by(&client_key, &ca)
+            .unwrap();
+
+        TestPki {
+            ca_pem: ca.pem(),
+            server_cert_pem: server_cert.pem(),
+            server_key_pem: server_key.serialize_pem(),
+            client_cert_pem: client_cert.pem(),
+            client_key_pem: client_key.serialize_pem(),
+        }
+    }
+
+    fn make_server_config(pki: &TestPki, require_client_cert: bool) -> rustls::ServerConfig {
+        let provider = crate::tls::crypto_provider();
+        let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+        let builder = if require_client_cert {
+            let mut roots = rustls::RootCertStore::empty();
+            for cert in crate::tls::load_certificates(&pki.ca_pem).unwrap() {
+                roots.add(cert).unwrap();
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .unwrap();
+            builder.with_client_cert_verifier(verifier)
+        } else {
+            builder.with_no_client_auth()
+        };
+        builder
+            .with_single_cert(
+                crate::tls::load_certificates(&pki.server_cert_pem).unwrap(),
+                crate::tls::load_private_key(&pki.server_key_pem).unwrap(),
+            )
+            .unwrap()
+    }
+
+    async fn echo<S>(stream: S)
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+    {
+        let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+            return;
+        };
+        while let Some(Ok(message)) = websocket.next().await {
+            if (message.is_text() || message.is_binary()) && websocket.send(message).await.is_err()
+            {
+                break;
+            }
+        }
+    }
+
+    /// Start a ws:// echo server on a random port and return its address.
+    async fn start_echo_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(echo(stream));
+            }
+        });
+
+        format!("ws://127.0.0.1:{port}")
+    }
+
+    /// Start a ws:// server that sends `messages` to every client and returns its address.
+    async fn start_scripted_server(messages: Vec<Message>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let messages = messages.clone();
+                tokio::spawn(async move {
+                    let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    for message in messages {
+                        if websocket.send(message).await.is_err() {
+                            return;
+                        }
+                    }
+                    while let Some(Ok(_)) = websocket.next().await {}
+                });
+            }
+        });
+
+        format!("ws://127.0.0.1:{port}")
+    }
+
+    /// Start a wss:// echo server on a random port and return its address.
+    async fn start_tls_echo_server(config: rustls::ServerConfig) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    if let Ok(stream) = acceptor.accept(stream).await {
+                        echo(stream).await;
+                    }
+                });
+            }
+        });
+
+        format!("wss://localhost:{port}")
+    }
+
+    /// Start a ws:// echo server that closes the first connection right after the
+    /// handshake and records the `X-Token` header of every handshake it sees.
+    /// With `single_use` the listener goes away after the first connection.
+    async fn start_flaky_server(single_use: bool) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let tokens = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = tokens.clone();
+
+        tokio::spawn(async move {
+            let mut first = true;
+            while let Ok((stream, _)) = listener.accept().await {
+                let seen = seen.clone();
+                let close_now = std::mem::take(&mut first);
+                let connection = tokio::spawn(async move {
+                    // The error type is fixed by tungstenite's handshake callback.
+                    #[allow(clippy::result_large_err)]
+                    let callback = |request: &Request, response: Response| {
+                        let token = request
+                            .headers()
+                            .get("X-Token")
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default();
+                        seen.lock().unwrap().push(token.to_owned());
+                        Ok(response)
+                    };
+                    let Ok(mut websocket) =
+                        tokio_tungstenite::accept_hdr_async(stream, callback).await
+                    else {
+                        return;
+                    };
+                    if close_now {
+                        let _ = websocket.close(None).await;
+                        return;
+                    }
+                    while let Some(Ok(message)) = websocket.next().await {
+                        if message.is_text() && websocket.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+                if single_use {
+                    let _ = connection.await;
+                    return;
+                }
+            }
+        });
+
+        (format!("ws://127.0.0.1:{port}"), tokens)
+    }
+
+    fn event_channel() -> (
+        EventSink,
+        tokio::sync::mpsc::UnboundedReceiver<(String, serde_json::Value)>,
+    ) {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let sink: EventSink =
+            Arc::new(move |event: &str, payload| tx.send((event.to_owned(), payload)).is_ok());
+        (sink, rx)
+    }
+
+    async fn next_event(
+        rx: &mut tokio::sync::mpsc::UnboundedReceiver<(String, serde_json::Value)>,
+    ) -> (String, serde_json::Value) {
+        tokio::time::timeout(Duration::from_secs(2), rx.recv())
+            .await
+            .expect("event")
+            .unwrap()
+    }
+
+    fn request_to(address: &str) -> RequestData {
+        RequestData::try_new(address.to_owned(), String::new()).unwrap()
+    }
+
+    async fn assert_echo(connection: &mut WebSocketConnection) {
+        send_message(connection, Message::Text("ping".into()))
+            .await
+            .unwrap();
+        let reply = next_message(connection, 1000).await.unwrap().unwrap();
+        assert_eq!(reply.to_text().unwrap(), "ping");
+    }
+
+    // ── parse_options ────────────────────────────────────────────────────────
+
+    #[test]
+    fn parse_options_empty_returns_defaults() {
+        let options = parse_options("").unwrap();
+        assert!(options.tls.client_config().is_ok());
+    }
+
+    #[test]
+    fn parse_options_rejects_client_cert_without_key() {
+        let pki = make_test_pki();
+        let raw = serde_json::json!({ "tls": { "clientCert": pki.client_cert_pem } }).to_string();
+        let error = parse_options(raw.as_str()).err().expect("should fail");
+        assert!(error.to_string().contains("clientKey"));
+    }
+
+    #[test]
+    fn parse_options_rejects_invalid_pem() {
+        let raw = serde_json::json!({
+            "tls": { "caCert": "-----BEGIN CERTIFICATE-----\nnot base64\n-----END CERTIFICATE-----" }
+        })
+        .to_string();
+        assert!(parse_options(raw.as_str()).is_err());
+    }
+
+    // ── frames ───────────────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn binary_frames_round_trip() {
+        let address = start_echo_server().await;
+        let mut connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+        let payload = vec![0u8, 159, 146, 150, 255];
+
+        send_message(&connection, Message::Binary(payload.clone().into()))
+            .await
+            .unwrap();
+        let message = next_message(&mut connection, 1000).await.unwrap();
+
+        assert_eq!(FrameType::of(message.as_ref()), FrameType::Binary);
+        assert_eq!(message.unwrap().into_data().as_ref(), payload.as_slice());
+    }
+
+    #[tokio::test]
+    async fn next_message_returns_none_on_timeout() {
+        let address = start_echo_server().await;
+        let mut connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        let message = next_message(&mut connection, 50).await.unwrap();
+
+        assert!(message.is_none());
+        assert_eq!(FrameType::of(message.as_ref()).as_str(), "");
+    }
+
+    // ── inbound queue ────────────────────────────────────────────────────────
+
+    fn numbered(count: usize) -> Vec<Message> {
+        (1..=count)
+            .map(|n| Message::Text(n.to_string().into()))
+            .collect()
+    }
+
+    fn queue_options(queue: serde_json::Value) -> WsOptions {
+        let raw = serde_json::json!({ "queue": queue }).to_string();
+        parse_options(raw.as_str()).unwrap()
+    }
+
+    /// Waits until the reader has queued `len` messages or the connection has closed.
+    async fn wait_for_queue(connection: &WebSocketConnection, len: usize) {
+        let inbound = connection.inbound().unwrap();
+        tokio::time::timeout(Duration::from_secs(2), async {
+            while inbound.len() < len && connection.state() != ConnectionState::Closed {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        })
+        .await
+        .expect("queue");
+    }
+
+    async fn take_texts(connection: &WebSocketConnection, max: usize) -> Vec<String> {
+        let inbound = connection.inbound().unwrap();
+        let messages = inbound.take(max, Duration::from_millis(100)).await.unwrap();
+        messages
+            .iter()
+            .map(|message| message.to_text().unwrap().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn parse_options_reads_queue() {
+        assert_eq!(parse_options("{}").unwrap().queue, QueueOptions::default());
+        assert_eq!(
+            queue_options(serde_json::json!({ "capacity": 5, "overflow": "disconnect" })).queue,
+            QueueOptions {
+                capacity: 5,
+                overflow: Overflow::Disconnect,
+            }
+        );
+        for queue in [
+            serde_json::json!({ "capacity": 0 }),
+            serde_json::json!({ "overflow": "wait" }),
+            serde_json::json!(100),
+        ] {
+            let raw = serde_json::json!({ "queue": queue }).to_string();
+            assert!(parse_options(raw.as_str()).is_err(), "{queue}");
+        }
+    }
+
+    #[tokio::test]
+    async fn queue_takes_messages_in_batches() {
+        let address = start_scripted_server(numbered(5)).await;
+        let connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+        wait_for_queue(&connection, 5).await;
+
+        assert_eq!(take_texts(&connection, 2).await, ["1", "2"]);
+        assert_eq!(connection.inbound().unwrap().len(), 3);
+        assert_eq!(take_texts(&connection, 10).await, ["3", "4", "5"]);
+        assert!(take_texts(&connection, 10).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn queue_overflow_drops_oldest_or_newest() {
+        let address = start_scripted_server(numbered(5)).await;
+        for (overflow, expected) in [
+            ("dropOldest", ["3", "4", "5"]),
+            ("dropNewest", ["1", "2", "3"]),
+        ] {
+            let options = queue_options(serde_json::json!({ "capacity": 3, "overflow": overflow }));
+            let connection = open(request_to(&address), &options, None).await.unwrap();
+            wait_for_queue(&connection, 3).await;
+            // Give the reader time to process the messages that do not fit.
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(take_texts(&connection, 10).await, expected, "{overflow}");
+            assert_eq!(connection.state(), ConnectionState::Open);
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_blocks_the_reader_by_default() {
+        let address = start_scripted_server(numbered(5)).await;
+        let options = queue_options(serde_json::json!({ "capacity": 2 }));
+        let connection = open(request_to(&address), &options, None).await.unwrap();
+        wait_for_queue(&connection, 2).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(connection.inbound().unwrap().len(), 2);
+
+        let mut received = Vec::new();
+        while received.len() < 5 {
+            received.extend(take_texts(&connection, 10).await);
+        }
+        assert_eq!(received, ["1", "2", "3", "4", "5"]);
+        assert_eq!(connection.state(), ConnectionState::Open);
+    }
+
+    #[tokio::test]
+    async fn queue_overflow_disconnects() {
+        let address = start_scripted_server(numbered(5)).await;
+        let options = queue_options(serde_json::json!({ "capacity": 2, "overflow": "disconnect" }));
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+        wait_for_state(&connection, ConnectionState::Closed).await;
+
+        assert_eq!(take_texts(&connection, 10).await, ["1", "2"]);
+        let error = next_message(&mut connection, 100)
+            .await
+            .expect_err("overflow");
+        assert!(error.to_string().contains("переполнена"), "{error}");
+        assert_eq!(next_message(&mut connection, 100).await.unwrap(), None);
+    }
+
+    // ── JSON-RPC ─────────────────────────────────────────────────────────────
+
+    /// Start a JSON-RPC server: `echo` returns its params after sending an unrelated
+    /// notification, `fail` returns an error and `silent` never answers.
+    async fn start_rpc_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    while let Some(Ok(Message::Text(text))) = websocket.next().await {
+                        let request: serde_json::Value = serde_json::from_str(&text).unwrap();
+                        let id = request["id"].clone();
+                        let response = match request["method"].as_str().unwrap() {
+                            "echo" => {
+                                let tick =
+                                    serde_json::json!({ "jsonrpc": "2.0", "method": "tick" });
+                                let tick = Message::Text(tick.to_string().into());
+                                websocket.send(tick).await.unwrap();
+                                serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": request["params"] })
+                            }
+                            "fail" => serde_json::json!({
+                                "jsonrpc": "2.0",
+                                "id": id,
+                                "error": { "code": -32601, "message": "Method not found", "data": "fail" },
+                            }),
+                            _ => continue,
+                        };
+                        let response = Message::Text(response.to_string().into());
+                        websocket.send(response).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        format!("ws://127.0.0.1:{port}")
+    }
+
+    #[tokio::test]
+    async fn rpc_returns_result_and_queues_other_messages() {
+        let address = start_rpc_server().await;
+        let mut connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        let params = serde_json::json!({ "symbols": ["A", "B"] });
+        let timeout = Duration::from_secs(1);
+        let result = rpc(&connection, "echo", Some(params.clone()), timeout).await;
+        assert_eq!(result.unwrap(), params);
+        let result = rpc(&connection, "echo", None, timeout).await;
+        assert_eq!(result.unwrap(), serde_json::Value::Null);
+
+        for _ in 0..2 {
+            let tick = next_message(&mut connection, 1000).await.unwrap().unwrap();
+            let tick: serde_json::Value = serde_json::from_str(tick.to_text().unwrap()).unwrap();
+            assert_eq!(tick["method"], "tick");
+        }
+    }
+
+    #[tokio::test]
+    async fn rpc_error_becomes_add_in_error() {
+        let address = start_rpc_server().await;
+        let connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        let error = rpc(&connection, "fail", None, Duration::from_secs(1))
+            .await
+            .expect_err("should fail");
+        assert_eq!(
+            error.to_string(),
+            r#"JSON-RPC ошибка -32601: Method not found ("fail")"#
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_times_out_and_forgets_the_call() {
+        let address = start_rpc_server().await;
+        let connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        let error = rpc(&connection, "silent", None, Duration::from_millis(50))
+            .await
+            .expect_err("should time out");
+        assert!(error.to_string().contains("50 мс"), "{error}");
+        assert!(connection.shared.rpc_calls.lock().unwrap().is_empty());
+    }
+
+    // ── events ───────────────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn events_mode_emits_messages_and_close() {
+        let address = start_scripted_server(vec![
+            Message::Text("hello".into()),
+            Message::Binary(vec![1u8, 2, 3].into()),
+            Message::Close(Some(CloseFrame {
+                code: CloseCode::Normal,
+                reason: "bye".into(),
+            })),
+        ])
+        .await;
+        let (events, mut rx) = event_channel();
+        let options = WsOptions {
+            events: true,
+            ..WsOptions::default()
+        };
+        let mut connection = open(request_to(&address), &options, Some(events))
+            .await
+            .unwrap();
+
+        let mut events = Vec::new();
+        for _ in 0..3 {
+            events.push(next_event(&mut rx).await);
+        }
+
+        assert_eq!(
+            events,
+            vec![
+                (
+                    "WS_MESSAGE".to_owned(),
+                    serde_json::json!({ "type": "text", "data": "hello" })
+                ),
+                (
+                    "WS_MESSAGE".to_owned(),
+                    serde_json::json!({ "type": "binary", "data": "AQID" })
+                ),
+                (
+                    "WS_CLOSE".to_owned(),
+                    serde_json::json!({ "code": 1000, "reason": "bye" })
+                ),
+            ]
+        );
+        assert!(next_message(&mut connection, 10).await.is_err());
+    }
+
+    #[test]
+    fn parse_options_reads_events_flag() {
+        assert!(!parse_options("{}").unwrap().events);
+        assert!(parse_options(r#"{"events":true}"#).unwrap().events);
+        assert!(parse_options(r#"{"events":"yes"}"#).is_err());
+    }
+
+    // ── reconnect ────────────────────────────────────────────────────────────
+
+    fn reconnect_options(max_attempts: u32, events: bool) -> WsOptions {
+        let raw = serde_json::json!({
+            "events": events,
+            "reconnect": { "maxAttempts": max_attempts, "initialDelayMs": 10, "jitter": 0 }
+        })
+        .to_string();
+        parse_options(raw.as_str()).unwrap()
+    }
+
+    fn request_with_token(address: &str) -> RequestData {
+        RequestData::try_new(address.to_owned(), r#"{"X-Token":"abc"}"#.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn parse_options_reads_reconnect_policy() {
+        assert_eq!(parse_options("{}").unwrap().reconnect, None);
+        assert_eq!(
+            parse_options(r#"{"reconnect":false}"#).unwrap().reconnect,
+            None
+        );
+        assert_eq!(
+            parse_options(r#"{"reconnect":true}"#).unwrap().reconnect,
+            Some(ReconnectPolicy::default())
+        );
+
+        let raw = r#"{"reconnect":{"maxAttempts":3,"initialDelayMs":100,"maxDelayMs":1000,"multiplier":1.5,"jitter":0}}"#;
+        assert_eq!(
+            parse_options(raw).unwrap().reconnect,
+            Some(ReconnectPolicy {
+                max_attempts: 3,
+                initial_delay: Duration::from_millis(100),
+                max_delay: Duration::from_millis(1000),
+                multiplier: 1.5,
+                jitter: 0.0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_options_rejects_invalid_reconnect_policy() {
+        assert!(parse_options(r#"{"reconnect":{"maxAttempts":0}}"#).is_err());
+        assert!(parse_options(r#"{"reconnect":{"jitter":2}}"#).is_err());
+        assert!(parse_options(r#"{"reconnect":{"multiplier":0.5}}"#).is_err());
+        assert!(parse_options(r#"{"reconnect":{"initialDelayMs":100,"maxDelayMs":10}}"#).is_err());
+        assert!(parse_options(r#"{"reconnect":"yes"}"#).is_err());
+    }
+
+    #[test]
+    fn reconnect_delay_grows_exponentially_up_to_max() {
+        let policy = ReconnectPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(300),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+        let delays: Vec<_> = (1..=4).map(|attempt| policy.delay(attempt)).collect();
+        assert_eq!(
+            delays,
+            [100, 200, 300, 300].map(Duration::from_millis).to_vec()
+        );
+    }
+
+    #[test]
+    fn reconnect_delay_stays_within_jitter() {
+        let policy = ReconnectPolicy {
+            jitter: 0.5,
+            ..ReconnectPolicy::default()
+        };
+        for _ in 0..100 {
+            let delay = policy.delay(1);
+            assert!(delay >= Duration::from_millis(250) && delay <= Duration::from_millis(750));
+        }
+    }
+
+    #[tokio::test]
+    async fn events_mode_reconnects_and_replays_headers() {
+        let (address, tokens) = start_flaky_server(false).await;
+        let (events, mut rx) = event_channel();
+        let connection = open(
+            request_with_token(&address),
+            &reconnect_options(3, true),
+            Some(events),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            next_event(&mut rx).await,
+            (
+                "WS_RECONNECTING".to_owned(),
+                serde_json::json!({ "attempt": 1, "maxAttempts": 3, "delayMs": 10, "error": "" })
+            )
+        );
+        assert_eq!(
+            next_event(&mut rx).await,
+            (
+                "WS_RECONNECTED".to_owned(),
+                serde_json::json!({ "attempt": 1 })
+            )
+        );
+
+        send_message(&connection, Message::Text("ping".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut rx).await,
+            (
+                "WS_MESSAGE".to_owned(),
+                serde_json::json!({ "type": "text", "data": "ping" })
+            )
+        );
+        assert_eq!(*tokens.lock().unwrap(), ["abc", "abc"]);
+    }
+
+    #[tokio::test]
+    async fn polling_mode_reconnects_in_background() {
+        let (address, tokens) = start_flaky_server(false).await;
+        let mut connection = open(
+            request_with_token(&address),
+            &reconnect_options(3, false),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let mut state = connection.shared.state.subscribe();
+        tokio::time::timeout(
+            Duration::from_secs(2),
+            state.wait_for(|_| connection.shared.generation.load(Ordering::SeqCst) == 1),
+        )
+        .await
+        .expect("reconnect")
+        .unwrap();
+
+        assert_eq!(connection.state(), ConnectionState::Open);
+        assert!(next_message(&mut connection, 10).await.unwrap().is_none());
+        assert_echo(&mut connection).await;
+        assert_eq!(*tokens.lock().unwrap(), ["abc", "abc"]);
+    }
+
+    #[tokio::test]
+    async fn events_mode_gives_up_after_max_attempts() {
+        let (address, _) = start_flaky_server(true).await;
+        let (events, mut rx) = event_channel();
+        let connection = open(
+            request_to(&address),
+            &reconnect_options(2, true),
+            Some(events),
+        )
+        .await
+        .unwrap();
+
+        let mut names = Vec::new();
+        loop {
+            let (event, payload) = next_event(&mut rx).await;
+            if event == "WS_RECONNECTING" && payload["attempt"] == 2 {
+                assert!(!payload["error"].as_str().unwrap().is_empty());
+            }
+            names.push(event.clone());
+            if event == "WS_CLOSE" {
+                break;
+            }
+        }
+
+        assert_eq!(names, ["WS_RECONNECTING", "WS_RECONNECTING", "WS_CLOSE"]);
+        assert_eq!(connection.state(), ConnectionState::Closed);
+    }
+
+    // ── heartbeat and state ──────────────────────────────────────────────────
+
+    /// Start a ws:// server that completes the handshake and then never reads,
+    /// so pings are never answered.
+    async fn start_silent_server() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let websocket = tokio_tungstenite::accept_async(stream).await;
+                    tokio::time::sleep(Duration::from_secs(10)).await;
+                    drop(websocket);
+                });
+            }
+        });
+
+        format!("ws://127.0.0.1:{port}")
+    }
+
+    async fn wait_for_state(connection: &WebSocketConnection, expected: ConnectionState) {
+        let mut state = connection.shared.state.subscribe();
+        tokio::time::timeout(
+            Duration::from_secs(2),
+            state.wait_for(|state| *state == expected),
+        )
+        .await
+        .expect("state")
+        .unwrap();
+    }
+
+    #[test]
+    fn parse_options_reads_heartbeat() {
+        assert_eq!(parse_options("{}").unwrap().heartbeat, None);
+        assert_eq!(
+            parse_options(r#"{"heartbeat":true}"#).unwrap().heartbeat,
+            Some(Heartbeat::default())
+        );
+        assert_eq!(
+            parse_options(r#"{"heartbeat":{"intervalMs":1000,"pongTimeoutMs":500}}"#)
+                .unwrap()
+                .heartbeat,
+            Some(Heartbeat {
+                interval: Duration::from_millis(1000),
+                pong_timeout: Duration::from_millis(500),
+            })
+        );
+        assert!(parse_options(r#"{"heartbeat":{"intervalMs":0}}"#).is_err());
+        assert!(parse_options(r#"{"heartbeat":{"pongTimeoutMs":-1}}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn control_frames_are_not_returned_as_messages() {
+        let address = start_scripted_server(vec![
+            Message::Ping("ping".into()),
+            Message::Pong("pong".into()),
+            Message::Text("hello".into()),
+        ])
+        .await;
+        let mut connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        let message = next_message(&mut connection, 1000).await.unwrap().unwrap();
+
+        assert_eq!(message, Message::Text("hello".into()));
+        assert!(next_message(&mut connection, 50).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn heartbeat_keeps_responsive_connection_open() {
+        let address = start_echo_server().await;
+        let options =
+            parse_options(r#"{"heartbeat":{"intervalMs":20,"pongTimeoutMs":50}}"#).unwrap();
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        assert_eq!(connection.state(), ConnectionState::Open);
+        assert_echo(&mut connection).await;
+    }
+
+    #[tokio::test]
+    async fn heartbeat_detects_missing_pong() {
+        let address = start_silent_server().await;
+        let options =
+            parse_options(r#"{"events":true,"heartbeat":{"intervalMs":20,"pongTimeoutMs":50}}"#)
+                .unwrap();
+        let (events, mut rx) = event_channel();
+        let connection = open(request_to(&address), &options, Some(events))
+            .await
+            .unwrap();
+
+        let (event, payload) = next_event(&mut rx).await;
+        assert_eq!(event, "WS_ERROR");
+        assert!(payload["error"].as_str().unwrap().contains("ping"));
+        assert_eq!(next_event(&mut rx).await.0, "WS_CLOSE");
+        assert_eq!(connection.state(), ConnectionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn heartbeat_is_not_blocked_by_a_pending_send() {
+        let address = start_silent_server().await;
+        let options =
+            parse_options(r#"{"events":true,"heartbeat":{"intervalMs":20,"pongTimeoutMs":50}}"#)
+                .unwrap();
+        let (events, mut rx) = event_channel();
+        let connection = open(request_to(&address), &options, Some(events))
+            .await
+            .unwrap();
+
+        // A send stuck on backpressure holds the sink for as long as the peer does not read.
+        let _sink = connection.shared.sender.lock().await;
+        let (event, payload) = next_event(&mut rx).await;
+        assert_eq!(event, "WS_ERROR");
+        assert!(payload["error"].as_str().unwrap().contains("ping"));
+    }
+
+    #[tokio::test]
+    async fn state_follows_server_close() {
+        let address = start_scripted_server(vec![Message::Close(None)]).await;
+        let mut connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        wait_for_state(&connection, ConnectionState::Closed).await;
+
+        assert!(next_message(&mut connection, 50).await.unwrap().is_none());
+        let error = send_message(&connection, Message::Text("late".into()))
+            .await
+            .expect_err("should fail");
+        assert_eq!(error.to_string(), "Соединение закрыто");
+    }
+
+    // ── close handshake ──────────────────────────────────────────────────────
+
+    /// Start a ws:// server that records the close frame sent by the client.
+    async fn start_close_recording_server() -> (String, Arc<std::sync::Mutex<Option<CloseFrame>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(std::sync::Mutex::new(None));
+        let seen = received.clone();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let Ok(mut websocket) = tokio_tungstenite::accept_async(stream).await else {
+                        return;
+                    };
+                    while let Some(Ok(message)) = websocket.next().await {
+                        if let Message::Close(frame) = message {
+                            *seen.lock().unwrap() = frame;
+                        }
+                    }
+                });
+            }
+        });
+
+        (format!("ws://127.0.0.1:{port}"), received)
+    }
+
+    #[tokio::test]
+    async fn close_performs_handshake_with_code_and_reason() {
+        let (address, received) = start_close_recording_server().await;
+        let mut connection = open(request_to(&address), &reconnect_options(3, false), None)
+            .await
+            .unwrap();
+
+        close(&mut connection, 4000, "done".to_owned())
+            .await
+            .unwrap();
+
+        let frame = received.lock().unwrap().clone().expect("close frame");
+        assert_eq!(u16::from(frame.code), 4000);
+        assert_eq!(frame.reason.as_str(), "done");
+        assert_eq!(connection.state(), ConnectionState::Closed);
+        assert_eq!(
+            connection.shared.peer_close(),
+            (Some(4000), "done".to_owned())
+        );
+        assert!(next_message(&mut connection, 10).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn close_raises_ws_close_without_reconnecting() {
+        let address = start_echo_server().await;
+        let (events, mut rx) = event_channel();
+        let mut connection = open(
+            request_to(&address),
+            &reconnect_options(3, true),
+            Some(events),
+        )
+        .await
+        .unwrap();
+
+        close(&mut connection, 1000, String::new()).await.unwrap();
+
+        assert_eq!(
+            next_event(&mut rx).await,
+            (
+                "WS_CLOSE".to_owned(),
+                serde_json::json!({ "code": 1000, "reason": "" })
+            )
+        );
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(rx.try_recv().is_err());
+        assert_eq!(connection.state(), ConnectionState::Closed);
+    }
+
+    #[tokio::test]
+    async fn close_rejects_invalid_code_and_reason() {
+        let address = start_echo_server().await;
+        let mut connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        assert!(close(&mut connection, 1005, String::new()).await.is_err());
+        assert!(close(&mut connection, 999, String::new()).await.is_err());
+        assert!(close(&mut connection, 1000, "x".repeat(124)).await.is_err());
+        assert_eq!(connection.state(), ConnectionState::Open);
+    }
+
+    #[tokio::test]
+    async fn server_close_code_and_reason_are_kept() {
+        let address = start_scripted_server(vec![Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: "maintenance".into(),
+        }))])
+        .await;
+        let connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        wait_for_state(&connection, ConnectionState::Closed).await;
+
+        assert_eq!(
+            connection.shared.peer_close(),
+            (Some(1001), "maintenance".to_owned())
+        );
+    }
+
+    // ── subprotocols and handshake response ──────────────────────────────────
+
+    /// Start a ws:// echo server that picks the first offered subprotocol it knows
+    /// and sets two cookies and a session header on the handshake response.
+    async fn start_subprotocol_server(supported: &'static [&'static str]) -> String {
+        use tokio_tungstenite::tungstenite::handshake::server::{Request, Response};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    // The error type is fixed by tungstenite's handshake callback.
+                    #[allow(clippy::result_large_err)]
+                    let callback = |request: &Request, mut response: Response| {
+                        let offered = request
+                            .headers()
+                            .get(SEC_WEBSOCKET_PROTOCOL)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default();
+                        let chosen = offered
+                            .split(',')
+                            .map(str::trim)
+                            .find(|name| supported.contains(name));
+                        let headers = response.headers_mut();
+                        if let Some(chosen) = chosen {
+                            headers.insert(SEC_WEBSOCKET_PROTOCOL, chosen.parse().unwrap());
+                        }
+                        headers.append("set-cookie", "a=1".parse().unwrap());
+                        headers.append("set-cookie", "b=2".parse().unwrap());
+                        headers.insert("x-session", "s-42".parse().unwrap());
+                        Ok(response)
+                    };
+                    if let Ok(websocket) =
+                        tokio_tungstenite::accept_hdr_async(stream, callback).await
+                    {
+                        let (sink, source) = websocket.split();
+                        let _ = source
+                            .filter(|message| {
+                                std::future::ready(matches!(message, Ok(m) if m.is_text()))
+                            })
+                            .forward(sink)
+                            .await;
+                    }
+                });
+            }
+        });
+
+        format!("ws://127.0.0.1:{port}")
+    }
+
+    fn subprotocol_options(subprotocols: &[&str]) -> WsOptions {
+        let raw = serde_json::json!({ "subprotocols": subprotocols }).to_string();
+        parse_options(raw.as_str()).unwrap()
+    }
+
+    #[test]
+    fn parse_options_rejects_invalid_subprotocols() {
+        for subprotocols in [
+            serde_json::json!("mqtt"),
+            serde_json::json!([1]),
+            serde_json::json!([""]),
+            serde_json::json!(["a, b"]),
+        ] {
+            let raw = serde_json::json!({ "subprotocols": subprotocols }).to_string();
+            assert!(parse_options(raw.as_str()).is_err(), "{subprotocols}");
+        }
+    }
+
+    #[tokio::test]
+    async fn negotiates_subprotocol_and_keeps_handshake_response() {
+        let address = start_subprotocol_server(&["mqtt"]).await;
+        let options = subprotocol_options(&["graphql-transport-ws", "mqtt"]);
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+
+        let handshake = connection.shared.handshake.lock().unwrap().to_json();
+        assert_eq!(handshake["status"], 101);
+        assert_eq!(handshake["subprotocol"], "mqtt");
+        assert_eq!(handshake["headers"]["sec-websocket-protocol"], "mqtt");
+        assert_eq!(
+            handshake["headers"]["set-cookie"],
+            serde_json::json!(["a=1", "b=2"])
+        );
+        assert_eq!(handshake["headers"]["x-session"], "s-42");
+        assert_echo(&mut connection).await;
+    }
+
+    #[tokio::test]
+    async fn handshake_without_subprotocols_has_none() {
+        let address = start_subprotocol_server(&["mqtt"]).await;
+        let connection = open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+
+        let handshake = connection.shared.handshake.lock().unwrap().to_json();
+        assert_eq!(handshake["subprotocol"], serde_json::Value::Null);
+        assert_eq!(handshake["headers"]["x-session"], "s-42");
+    }
+
+    #[tokio::test]
+    async fn connect_fails_when_server_rejects_every_subprotocol() {
+        let address = start_subprotocol_server(&["mqtt"]).await;
+        let options = subprotocol_options(&["graphql-transport-ws"]);
+        let result = open(request_to(&address), &options, None).await;
+        assert!(result.is_err());
+    }
+
+    // ── proxy ────────────────────────────────────────────────────────────────
+
+    /// Start an HTTP CONNECT proxy that records the head of every request. With
+    /// `authorization` set, requests without that `Proxy-Authorization` get 407.
+    async fn start_connect_proxy(
+        authorization: Option<&'static str>,
+    ) -> (String, Arc<std::sync::Mutex<Vec<String>>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = requests.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut client, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        match client.read_u8().await {
+                            Ok(byte) => head.push(byte),
+                            Err(_) => return,
+                        }
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    seen.lock().unwrap().push(head.clone());
+                    if let Some(expected) = authorization {
+                        let header = format!("Proxy-Authorization: {expected}\r\n");
+                        if !head.contains(&header) {
+                            let _ = client
+                                .write_all(b"HTTP/1.1 407 Proxy Authentication Required\r\n\r\n")
+                                .await;
+                            return;
+                        }
+                    }
+                    let target = head.split_whitespace().nth(1).unwrap().to_owned();
+                    let Ok(mut upstream) = TcpStream::connect(target).await else {
+                        let _ = client.write_all(b"HTTP/1.1 502 Bad Gateway\r\n\r\n").await;
+                        return;
+                    };
+                    let _ = client
+                        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+                        .await;
+                    let _ = tokio::io::copy_bidirectional(&mut client, &mut upstream).await;
+                });
+            }
+        });
+
+        (format!("http://127.0.0.1:{port}"), requests)
+    }
+
+    fn proxy_options(proxy: serde_json::Value) -> WsOptions {
+        let raw = serde_json::json!({ "proxy": proxy }).to_string();
+        parse_options(raw.as_str()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn connects_through_connect_proxy() {
+        let address = start_echo_server().await;
+        let (proxy, requests) = start_connect_proxy(None).await;
+        let options = proxy_options(serde_json::json!({ "url": proxy }));
+
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+        assert_echo(&mut connection).await;
+
+        let requests = requests.lock().unwrap();
+        let target = address.trim_start_matches("ws://");
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].starts_with(&format!("CONNECT {target} HTTP/1.1\r\n")));
+        assert!(!requests[0].contains("Proxy-Authorization"));
+    }
+
+    #[tokio::test]
+    async fn wss_connects_through_connect_proxy() {
+        let pki = make_test_pki();
+        let address = start_tls_echo_server(make_server_config(&pki, false)).await;
+        let (proxy, requests) = start_connect_proxy(None).await;
+        let raw = serde_json::json!({
+            "tls": { "caCert": pki.ca_pem },
+            "proxy": { "url": proxy },
+        })
+        .to_string();
+        let options = parse_options(raw.as_str()).unwrap();
+
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+        assert_echo(&mut connection).await;
+        let port = address.rsplit(':').next().unwrap();
+        let connect_line = format!("CONNECT localhost:{port} HTTP/1.1\r\n");
+        assert!(requests.lock().unwrap()[0].starts_with(&connect_line));
+    }
+
+    #[tokio::test]
+    async fn proxy_basic_auth() {
+        let address = start_echo_server().await;
+        let (proxy, _) = start_connect_proxy(Some("Basic dXNlcjpwYXNz")).await;
+
+        let options = proxy_options(serde_json::json!({
+            "url": proxy, "user": "user", "password": "pass",
+        }));
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+        assert_echo(&mut connection).await;
+
+        let options = proxy_options(serde_json::json!({
+            "url": proxy, "user": "user", "password": "wrong",
+        }));
+        let error = open(request_to(&address), &options, None)
+            .await
+            .err()
+            .expect("should fail");
+        assert!(error.to_string().contains("407"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn proxy_bypass_connects_directly() {
+        let address = start_echo_server().await;
+        let (proxy, requests) = start_connect_proxy(None).await;
+        let options = proxy_options(serde_json::json!({ "url": proxy, "bypass": ["127.0.0.1"] }));
+
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+        assert_echo(&mut connection).await;
+        assert!(requests.lock().unwrap().is_empty());
+    }
+
+    // ── compression ──────────────────────────────────────────────────────────
+
+    /// Offered extensions and the RSV1 bit of every data frame a deflate server received.
+    type DeflateLog = Arc<std::sync::Mutex<(String, Vec<bool>)>>;
+
+    /// Start a hand-written ws:// server that accepts permessage-deflate with a limited
+    /// client window, sends a compressed greeting in the same packet as the handshake
+    /// response and echoes text messages back compressed and split into two fragments.
+    /// One compressor is used for the whole connection, so context takeover is exercised.
+    async fn start_deflate_server() -> (String, DeflateLog) {
+        use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+
+        fn frame(fin: bool, rsv1: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+            let mut frame = vec![(fin as u8) << 7 | (rsv1 as u8) << 6 | opcode];
+            match payload.len() {
+                len @ 0..=125 => frame.push(len as u8),
+                len => {
+                    frame.push(126);
+                    frame.extend_from_slice(&(len as u16).to_be_bytes());
+                }
+            }
+            frame.extend_from_slice(payload);
+            frame
+        }
+
+        fn compress(compress: &mut Compress, data: &[u8]) -> Vec<u8> {
+            let mut output = Vec::with_capacity(data.len() + 64);
+            compress
+                .compress_vec(data, &mut output, FlushCompress::Sync)
+                .unwrap();
+            output.truncate(output.len() - 4);
+            output
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let log = DeflateLog::default();
+        let seen = log.clone();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let seen = seen.clone();
+                tokio::spawn(async move {
+                    let mut head = Vec::new();
+                    while !head.ends_with(b"\r\n\r\n") {
+                        head.push(stream.read_u8().await.unwrap());
+                    }
+                    let head = String::from_utf8(head).unwrap();
+                    let header = |name: &str| {
+                        head.lines()
+                            .filter_map(|line| line.split_once(": "))
+                            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                            .map(|(_, value)| value.to_owned())
+                            .unwrap_or_default()
+                    };
+                    seen.lock().unwrap().0 = header("Sec-WebSocket-Extensions");
+
+                    let mut deflate =
+                        Compress::new_with_window_bits(Compression::default(), false, 10);
+                    let mut inflate = Decompress::new(false);
+                    let accept = derive_accept_key(header("Sec-WebSocket-Key").as_bytes());
+                    let mut greeting = format!(
+                        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+                         Connection: Upgrade\r\nSec-WebSocket-Accept: {accept}\r\n\
+                         Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=10\r\n\r\n"
+                    )
+                    .into_bytes();
+                    let payload = compress(&mut deflate, "greeting ".repeat(50).as_bytes());
+                    greeting.extend(frame(true, true, 0x1, &payload));
+                    stream.write_all(&greeting).await.unwrap();
+
+                    loop {
+                        let mut head = [0u8; 2];
+                        if stream.read_exact(&mut head).await.is_err() {
+                            return;
+                        }
+                        let len = match head[1] & 0x7f {
+                            126 => stream.read_u16().await.unwrap() as usize,
+                            127 => stream.read_u64().await.unwrap() as usize,
+                            len => len as usize,
+                        };
+                        let mut mask = [0u8; 4];
+                        stream.read_exact(&mut mask).await.unwrap();
+                        let mut payload = vec![0; len];
+                        stream.read_exact(&mut payload).await.unwrap();
+                        payload
+                            .iter_mut()
+                            .enumerate()
+                            .for_each(|(i, byte)| *byte ^= mask[i % 4]);
+
+                        let (rsv1, opcode) = (head[0] & 0x40 != 0, head[0] & 0x0f);
+                        if opcode != 0x1 {
+                            return;
+                        }
+                        seen.lock().unwrap().1.push(rsv1);
+                        let text = if rsv1 {
+                            payload.extend_from_slice(&[0, 0, 0xff, 0xff]);
+                            let mut text = Vec::with_capacity(64 * 1024);
+                            inflate
+                                .decompress_vec(&payload, &mut text, FlushDecompress::Sync)
+                                .unwrap();
+                            text
+                        } else {
+                            payload
+                        };
+                        let reply = compress(&mut deflate, &text);
+                        let (first, second) = reply.split_at(reply.len() / 2);
+                        let mut frames = frame(false, true, 0x1, first);
+                        frames.extend(frame(true, false, 0x0, second));
+                        stream.write_all(&frames).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        (format!("ws://127.0.0.1:{port}"), log)
+    }
+
+    fn compression_options(compression: serde_json::Value) -> WsOptions {
+        let raw = serde_json::json!({ "compression": compression }).to_string();
+        parse_options(raw.as_str()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn compressed_messages_are_inflated_and_sent_compressed() {
+        let (address, log) = start_deflate_server().await;
+        let options = compression_options(true.into());
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+
+        let greeting = next_message(&mut connection, 1000).await.unwrap().unwrap();
+        assert_eq!(greeting.to_text().unwrap(), "greeting ".repeat(50));
+        for text in [
+            "quote ".repeat(2000),
+            "quote ".repeat(2000),
+            "short".to_owned(),
+        ] {
+            send_message(&connection, Message::Text(text.clone().into()))
+                .await
+                .unwrap();
+            let reply = next_message(&mut connection, 1000).await.unwrap().unwrap();
+            assert_eq!(reply.to_text().unwrap(), text);
+        }
+
+        let (offer, compressed) = log.lock().unwrap().clone();
+        assert_eq!(offer, "permessage-deflate; client_max_window_bits");
+        assert_eq!(compressed, vec![true, true, true]);
+        let handshake = connection.shared.handshake.lock().unwrap().to_json();
+        assert_eq!(
+            handshake["headers"]["sec-websocket-extensions"],
+            "permessage-deflate; client_max_window_bits=10"
+        );
+    }
+
+    #[tokio::test]
+    async fn compression_is_skipped_when_server_declines() {
+        let address = start_echo_server().await;
+        let mut connection = open(
+            request_to(&address),
+            &compression_options(true.into()),
+            None,
+        )
+        .await
+        .unwrap();
+        assert_echo(&mut connection).await;
+    }
+
+    #[test]
+    fn parse_options_rejects_invalid_compression() {
+        let raw = serde_json::json!({ "compression": { "clientMaxWindowBits": 16 } }).to_string();
+        assert!(parse_options(raw.as_str()).is_err());
+    }
+
+    // ── timeouts ─────────────────────────────────────────────────────────────
+
+    /// Start a TCP listener that accepts connections and never answers.
+    async fn start_mute_listener() -> (u16, Arc<std::sync::Mutex<Vec<TcpStream>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let kept = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                kept.lock().unwrap().push(stream);
+            }
+        });
+        (port, accepted)
+    }
+
+    fn timeout_options(timeouts: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "timeouts": timeouts })
+    }
+
+    #[test]
+    fn parse_options_reads_timeouts() {
+        assert_eq!(parse_options("{}").unwrap().timeouts, Timeouts::default());
+        let raw = timeout_options(serde_json::json!({ "connectMs": 0, "sendMs": 250 }));
+        assert_eq!(
+            parse_options(&raw.to_string()).unwrap().timeouts,
+            Timeouts {
+                connect: None,
+                handshake: Some(Duration::from_secs(30)),
+                send: Some(Duration::from_millis(250)),
+            }
+        );
+        for timeouts in [serde_json::json!(1000), serde_json::json!({ "sendMs": -1 })] {
+            assert!(parse_options(&timeout_options(timeouts).to_string()).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn connect_timeout_covers_proxy_exchange() {
+        let address = start_echo_server().await;
+        let (port, _accepted) = start_mute_listener().await;
+        let mut raw = timeout_options(serde_json::json!({ "connectMs": 100 }));
+        raw["proxy"] = serde_json::json!({ "url": format!("http://127.0.0.1:{port}") });
+        let options = parse_options(&raw.to_string()).unwrap();
+
+        let error = open(request_to(&address), &options, None)
+            .await
+            .err()
+            .expect("should time out");
+        assert!(
+            error.to_string().starts_with("WS_CONNECT_TIMEOUT:"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_timeout_when_server_does_not_answer() {
+        let (port, _accepted) = start_mute_listener().await;
+        let raw = timeout_options(serde_json::json!({ "handshakeMs": 100 }));
+        let options = parse_options(&raw.to_string()).unwrap();
+
+        let address = format!("ws://127.0.0.1:{port}");
+        let error = open(request_to(&address), &options, None)
+            .await
+            .err()
+            .expect("should time out");
+        assert!(
+            error.to_string().starts_with("WS_HANDSHAKE_TIMEOUT:"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn send_timeout_when_server_stops_reading() {
+        let address = start_silent_server().await;
+        let raw = timeout_options(serde_json::json!({ "sendMs": 100 }));
+        let options = parse_options(&raw.to_string()).unwrap();
+        let connection = open(request_to(&address), &options, None).await.unwrap();
+
+        let chunk = Message::Binary(vec![0u8; 1 << 20].into());
+        let mut error = None;
+        for _ in 0..64 {
+            if let Err(failed) = send_message(&connection, chunk.clone()).await {
+                error = Some(failed);
+                break;
+            }
+        }
+        let error = error.expect("socket buffers should fill up");
+        assert!(error.to_string().starts_with("WS_SEND_TIMEOUT:"), "{error}");
+    }
+
+    // ── connection table ─────────────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn connection_table_keeps_connections_apart() {
+        let address = start_echo_server().await;
+        let (events, mut rx) = event_channel();
+        let event_options = WsOptions {
+            events: true,
+            ..WsOptions::default()
+        };
+        let mut websockets = WsConnections::default();
+
+        let polled = websockets
+            .open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+        let evented = websockets
+            .open(request_to(&address), &event_options, Some(events))
+            .await
+            .unwrap();
+        assert_eq!((polled, evented), (1, 2));
+
+        let connection = &websockets.connections[&evented];
+        send_message(connection, Message::Text("second".into()))
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut rx).await,
+            (
+                "WS_MESSAGE".to_owned(),
+                serde_json::json!({ "type": "text", "data": "second", "connection": 2 })
+            )
+        );
+
+        let connection = websockets.connections.get_mut(&polled).unwrap();
+        assert_echo(connection).await;
+
+        websockets.connections.remove(&polled);
+        let handle = websockets
+            .open(request_to(&address), &WsOptions::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(handle, 3);
+    }
+
+    // ── wss:// integration tests ─────────────────────────────────────────────
+
+    #[tokio::test]
+    async fn wss_connects_with_custom_ca() {
+        let pki = make_test_pki();
+        let address = start_tls_echo_server(make_server_config(&pki, false)).await;
+        let raw = serde_json::json!({ "tls": { "caCert": pki.ca_pem } }).to_string();
+        let options = parse_options(raw.as_str()).unwrap();
+
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+        assert_echo(&mut connection).await;
+    }
+
+    #[tokio::test]
+    async fn wss_rejects_unknown_issuer_by_default() {
+        let pki = make_test_pki();
+        let address = start_tls_echo_server(make_server_config(&pki, false)).await;
+
+        let result = open(request_to(&address), &WsOptions::default(), None).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn wss_accepts_invalid_certs_when_allowed() {
+        let pki = make_test_pki();
+        let address = start_tls_echo_server(make_server_config(&pki, false)).await;
+        let options = parse_options(r#"{"tls":{"acceptInvalidCerts":true}}"#).unwrap();
+
+        let mut connection = open(request_to(&address), &options, None).await.unwrap();
+        assert_echo(&mut connection).await;
+    }
+
+    #[tokio::test]
+    async fn wss_presents_client_certificate() {
+        let pki = make_test_pki();
+        let address = start_tls_echo_server(make_server_config(&pki, true)).await;
+
+        let raw = serde_json::json!({ "tls": { "caCert": pki.ca_pem } }).to_string();
+        let without_cert = parse_options(raw.as_str()).unwrap();
+        assert!(open(request_to(&address), &without_cert, None)
+            .await
+            .is_err());
+
+        let raw = serde_json::json!({
+            "tls": {
+                "caCert": pki.ca_pem,
+                "clientCert": pki.client_cert_pem,
+                "clientKey": pki.client_key_pem,
+            }
+        })
+        .to_string();
+        let with_cert = parse_options(raw.as_str()).unwrap();
+        let mut connection = open(request_to(&address), &with_cert, None).await.unwrap();
+        assert_echo(&mut connection).await;
+    }
+}
@@ -0,0 +1,667 @@
+//! permessage-deflate (RFC 7692) for the ws client.
+//!
+//! tungstenite rejects frames with the RSV1 bit set, so the extension lives in a stream
+//! adapter below it: `DeflateStream` watches the handshake response for the negotiated
+//! parameters, inflates compressed messages before tungstenite reads them and compresses
+//! the messages tungstenite writes.
+
+use std::error::Error;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::{Buf, BytesMut};
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{option_bool, option_u64};
+
+/// Same limit as tungstenite's default `max_message_size`.
+const MAX_MESSAGE_BYTES: usize = 64 << 20;
+/// The handshake response is a short header block; give up on anything longer.
+const MAX_HANDSHAKE_BYTES: usize = 64 << 10;
+/// `poll_write` stops buffering and drains once this much compressed data is pending.
+const WRITE_HIGH_WATER_BYTES: usize = 64 << 10;
+const READ_CHUNK_BYTES: usize = 16 << 10;
+/// Appended to a message before inflating and stripped after compressing (RFC 7692, 7.2.1).
+const DEFLATE_TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_BINARY: u8 = 0x2;
+
+/// Compression settings parsed from the `compression` section of the options JSON.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct DeflateOptions {
+    client_max_window_bits: Option<u8>,
+    server_max_window_bits: Option<u8>,
+    client_no_context_takeover: bool,
+    server_no_context_takeover: bool,
+}
+
+impl DeflateOptions {
+    /// `true` enables compression with the defaults, `false` and `null` disable it.
+    pub(crate) fn from_json(value: &Value) -> Result<Option<Self>, Box<dyn Error>> {
+        let obj = match value {
+            Value::Bool(true) => return Ok(Some(Self::default())),
+            Value::Bool(false) | Value::Null => return Ok(None),
+            Value::Object(obj) => obj,
+            _ => {
+                return Err("Параметр compression должен быть булевым или JSON объектом"
+                    .to_owned()
+                    .into())
+            }
+        };
+        let window_bits = |key: &str| -> Result<Option<u8>, Box<dyn Error>> {
+            match option_u64(obj, key)? {
+                None => Ok(None),
+                Some(bits @ 8..=15) => Ok(Some(bits as u8)),
+                Some(_) => Err(format!("Параметр {key} должен быть от 8 до 15").into()),
+            }
+        };
+        Ok(Some(Self {
+            client_max_window_bits: window_bits("clientMaxWindowBits")?,
+            server_max_window_bits: window_bits("serverMaxWindowBits")?,
+            client_no_context_takeover: option_bool(obj, "clientNoContextTakeover")?
+                .unwrap_or(false),
+            server_no_context_takeover: option_bool(obj, "serverNoContextTakeover")?
+                .unwrap_or(false),
+        }))
+    }
+
+    /// Value of the `Sec-WebSocket-Extensions` request header.
+    pub(crate) fn offer(&self) -> String {
+        // `client_max_window_bits` is always offered: it lets the server limit our window.
+        let mut offer = match self.client_max_window_bits {
+            Some(bits) => format!("permessage-deflate; client_max_window_bits={bits}"),
+            None => "permessage-deflate; client_max_window_bits".to_owned(),
+        };
+        if let Some(bits) = self.server_max_window_bits {
+            offer.push_str(&format!("; server_max_window_bits={bits}"));
+        }
+        if self.client_no_context_takeover {
+            offer.push_str("; client_no_context_takeover");
+        }
+        if self.server_no_context_takeover {
+            offer.push_str("; server_no_context_takeover");
+        }
+        offer
+    }
+
+    /// Reads the parameters the server accepted from the handshake response head.
+    /// `None` when the response is not a successful upgrade or the server declined
+    /// the extension.
+    fn negotiate(&self, head: &[u8]) -> Option<Negotiated> {
+        let head = std::str::from_utf8(head).ok()?;
+        let mut lines = head.split("\r\n");
+        let status = lines.next()?.split_whitespace().nth(1)?;
+        if status != "101" {
+            return None;
+        }
+        let extension = lines
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-extensions"))
+            .flat_map(|(_, value)| value.split(','))
+            .find(|extension| {
+                let name = extension.split(';').next().unwrap_or_default();
+                name.trim().eq_ignore_ascii_case("permessage-deflate")
+            })?;
+
+        let mut negotiated = Negotiated {
+            client_window_bits: self.client_max_window_bits.unwrap_or(15),
+            client_no_context_takeover: self.client_no_context_takeover,
+            server_no_context_takeover: false,
+        };
+        for param in extension.split(';').skip(1) {
+            let (name, value) = match param.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                None => (param.trim(), None),
+            };
+            match name {
+                "client_no_context_takeover" => negotiated.client_no_context_takeover = true,
+                "server_no_context_takeover" => negotiated.server_no_context_takeover = true,
+                "client_max_window_bits" => {
+                    if let Some(bits) = value.and_then(|value| value.parse::<u8>().ok()) {
+                        negotiated.client_window_bits = negotiated.client_window_bits.min(bits);
+                    }
+                }
+                // The inflater always uses the largest window, which decodes any smaller one.
+                _ => {}
+            }
+        }
+        Some(negotiated)
+    }
+}
+
+/// Parameters accepted by the server.
+#[derive(Debug, PartialEq)]
+struct Negotiated {
+    client_window_bits: u8,
+    client_no_context_takeover: bool,
+    server_no_context_takeover: bool,
+}
+
+enum State {
+    /// Collecting the handshake response head.
+    Handshake(DeflateOptions),
+    /// Compression is on for this connection.
+    Deflate(Box<Codec>),
+    /// Compression was not offered or not accepted: bytes go through untouched.
+    Passthrough,
+}
+
+struct Codec {
+    inflate: Decompress,
+    reset_inflate: bool,
+    /// `None` when the server limited our window to 8 bits, which the deflate
+    /// implementation does not support; messages are then sent uncompressed.
+    deflate: Option<Compress>,
+    reset_deflate: bool,
+    /// Opcode and payload of a compressed message whose final fragment has not arrived.
+    message: Option<(u8, Vec<u8>)>,
+}
+
+impl Codec {
+    fn new(negotiated: Negotiated) -> Self {
+        let deflate = (negotiated.client_window_bits >= 9).then(|| {
+            Compress::new_with_window_bits(
+                Compression::default(),
+                false,
+                negotiated.client_window_bits,
+            )
+        });
+        Self {
+            inflate: Decompress::new(false),
+            reset_inflate: negotiated.server_no_context_takeover,
+            deflate,
+            reset_deflate: negotiated.client_no_context_takeover,
+            message: None,
+        }
+    }
+
+    /// Turns frames from the server into frames tungstenite understands, reassembling
+    /// and inflating compressed messages; other frames are copied unchanged.
+    fn decode(&mut self, input: &mut BytesMut, output: &mut BytesMut) -> io::Result<()> {
+        while let Some(head) = FrameHead::parse(input)? {
+            if input.len() < head.len() {
+                break;
+            }
+            let frame = input.split_to(head.len());
+            let payload = || head.payload(&frame);
+            match (&mut self.message, head.opcode) {
+                (None, OPCODE_TEXT | OPCODE_BINARY) if head.rsv1 => {
+                    self.message = Some((head.opcode, payload()));
+                }
+                (Some((_, data)), OPCODE_CONTINUATION) => {
+                    data.extend_from_slice(&payload());
+                    if data.len() > MAX_MESSAGE_BYTES {
+                        return Err(too_large());
+                    }
+                }
+                // Control frames, uncompressed messages and anything tungstenite should reject.
+                _ => {
+                    output.extend_from_slice(&frame);
+                    continue;
+                }
+            }
+            if head.fin {
+                let (opcode, data) = self.message.take().unwrap_or_default();
+                let data = self.inflate_message(data)?;
+                FrameHead::write(output, opcode, false, None, &data);
+            }
+        }
+        Ok(())
+    }
+
+    fn inflate_message(&mut self, mut data: Vec<u8>) -> io::Result<Vec<u8>> {
+        data.extend_from_slice(&DEFLATE_TAIL);
+        let mut output = Vec::with_capacity(data.len() * 4);
+        let start = self.inflate.total_in();
+        loop {
+            if output.len() == output.capacity() {
+                output.reserve(READ_CHUNK_BYTES);
+            }
+            let consumed = (self.inflate.total_in() - start) as usize;
+            let status = self
+                .inflate
+                .decompress_vec(&data[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            if output.len() > MAX_MESSAGE_BYTES {
+                return Err(too_large());
+            }
+            // A sync flush is complete once the input is used up and the output has room left.
+            let consumed = (self.inflate.total_in() - start) as usize;
+            if consumed == data.len() && output.len() < output.capacity() {
+                break;
+            }
+            match status {
+                Status::StreamEnd => break,
+                // No progress although there is room for output: the data is truncated.
+                Status::BufError if output.len() < output.capacity() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Некорректные сжатые данные",
+                    ))
+                }
+                _ => {}
+            }
+        }
+        if self.reset_inflate {
+            self.inflate.reset(false);
+        }
+        Ok(output)
+    }
+
+    /// Compresses unfragmented data frames written by tungstenite; other frames are
+    /// copied unchanged. Client frames are masked, so the payload is unmasked before
+    /// compressing and masked again with the same key.
+    fn encode(&mut self, input: &mut BytesMut, output: &mut BytesMut) -> io::Result<()> {
+        while let Some(head) = FrameHead::parse(input)? {
+            if input.len() < head.len() {
+                break;
+            }
+            let frame = input.split_to(head.len());
+            let compressible =
+                matches!(head.opcode, OPCODE_TEXT | OPCODE_BINARY) && head.fin && !head.rsv1;
+            match (&mut self.deflate, compressible) {
+                (Some(deflate), true) => {
+                    let data = deflate_message(deflate, &head.payload(&frame))?;
+                    if self.reset_deflate {
+                        deflate.reset();
+                    }
+                    FrameHead::write(output, head.opcode, true, head.mask, &data);
+                }
+                _ => output.extend_from_slice(&frame),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn deflate_message(deflate: &mut Compress, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut output = Vec::with_capacity(data.len() / 2 + 64);
+    let start = deflate.total_in();
+    loop {
+        if output.len() == output.capacity() {
+            output.reserve(READ_CHUNK_BYTES);
+        }
+        let consumed = (deflate.total_in() - start) as usize;
+        deflate
+            .compress_vec(&data[consumed..], &mut output, FlushCompress::Sync)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let consumed = (deflate.total_in() - start) as usize;
+        if consumed == data.len() && output.len() < output.capacity() {
+            break;
+        }
+    }
+    if output.ends_with(&DEFLATE_TAIL) {
+        output.truncate(output.len() - DEFLATE_TAIL.len());
+    }
+    Ok(output)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Распакованное сообщение больше {MAX_MESSAGE_BYTES} байт"),
+    )
+}
+
+/// Header of a WebSocket frame (RFC 6455, 5.2).
+struct FrameHead {
+    fin: bool,
+    rsv1: bool,
+    opcode: u8,
+    mask: Option<[u8; 4]>,
+    header_len: usize,
+    payload_len: usize,
+}
+
+impl FrameHead {
+    /// `None` until the whole header has been received.
+    fn parse(input: &[u8]) -> io::Result<Option<Self>> {
+        let [first, second, ..] = *input else {
+            return Ok(None);
+        };
+        let masked = second & 0x80 != 0;
+        let (length_bytes, payload_len) = match second & 0x7f {
+            126 => (2, None),
+            127 => (8, None),
+            len => (0, Some(len as u64)),
+        };
+        let header_len = 2 + length_bytes + if masked { 4 } else { 0 };
+        if input.len() < header_len {
+            return Ok(None);
+        }
+        let payload_len = payload_len.unwrap_or_else(|| {
+            input[2..2 + length_bytes]
+                .iter()
+                .fold(0u64, |len, byte| len << 8 | *byte as u64)
+        });
+        if payload_len > MAX_MESSAGE_BYTES as u64 {
+            return Err(too_large());
+        }
+        let mask = masked.then(|| {
+            let key = &input[header_len - 4..header_len];
+            [key[0], key[1], key[2], key[3]]
+        });
+        Ok(Some(Self {
+            fin: first & 0x80 != 0,
+            rsv1: first & 0x40 != 0,
+            opcode: first & 0x0f,
+            mask,
+            header_len,
+            payload_len: payload_len as usize,
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.header_len + self.payload_len
+    }
+
+    fn payload(&self, frame: &[u8]) -> Vec<u8> {
+        let mut payload = frame[self.header_len..self.len()].to_vec();
+        if let Some(mask) = self.mask {
+            apply_mask(&mut payload, mask);
+        }
+        payload
+    }
+
+    /// Writes an unfragmented frame; `rsv1` marks a compressed message.
+    fn write(output: &mut BytesMut, opcode: u8, rsv1: bool, mask: Option<[u8; 4]>, data: &[u8]) {
+        let rsv1_bit = if rsv1 { 0x40 } else { 0 };
+        output.extend_from_slice(&[0x80 | rsv1_bit | opcode]);
+        let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+        match data.len() {
+            len @ 0..=125 => output.extend_from_slice(&[mask_bit | len as u8]),
+            len @ 126..=0xffff => {
+                output.extend_from_slice(&[mask_bit | 126]);
+                output.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                output.extend_from_slice(&[mask_bit | 127]);
+                output.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        match mask {
+            Some(mask) => {
+                output.extend_from_slice(&mask);
+                let start = output.len();
+                output.extend_from_slice(data);
+                apply_mask(&mut output[start..], mask);
+            }
+            None => output.extend_from_slice(data),
+        }
+    }
+}
+
+fn apply_mask(data: &mut [u8], mask: [u8; 4]) {
+    for (i, byte) in data.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+}
+
+/// Stream between the socket and tungstenite that implements permessage-deflate.
+pub(crate) struct DeflateStream<S> {
+    inner: S,
+    state: State,
+    /// Bytes read from the socket and not yet decoded.
+    read_raw: BytesMut,
+    /// Decoded bytes for tungstenite.
+    read_ready: BytesMut,
+    /// Bytes from tungstenite that do not yet form a whole frame.
+    write_raw: BytesMut,
+    /// Encoded bytes waiting to be written to the socket.
+    write_ready: BytesMut,
+}
+
+impl<S> DeflateStream<S> {
+    /// Without `options` the stream passes every byte through.
+    pub(crate) fn new(inner: S, options: Option<DeflateOptions>) -> Self {
+        Self {
+            inner,
+            state: match options {
+                Some(options) => State::Handshake(options),
+                None => State::Passthrough,
+            },
+            read_raw: BytesMut::new(),
+            read_ready: BytesMut::new(),
+            write_raw: BytesMut::new(),
+            write_ready: BytesMut::new(),
+        }
+    }
+
+    fn decode(&mut self) -> io::Result<()> {
+        if let State::Handshake(options) = &self.state {
+            let Some(end) = find_head_end(&self.read_raw) else {
+                if self.read_raw.len() > MAX_HANDSHAKE_BYTES {
+                    self.state = State::Passthrough;
+                    self.read_ready.extend_from_slice(&self.read_raw.split());
+                }
+                return Ok(());
+            };
+            let head = self.read_raw.split_to(end);
+            self.state = match options.negotiate(&head) {
+                Some(negotiated) => State::Deflate(Box::new(Codec::new(negotiated))),
+                None => State::Passthrough,
+            };
+            self.read_ready.extend_from_slice(&head);
+        }
+        match &mut self.state {
+            State::Deflate(codec) => codec.decode(&mut self.read_raw, &mut self.read_ready),
+            _ => {
+                self.read_ready.extend_from_slice(&self.read_raw.split());
+                Ok(())
+            }
+        }
+    }
+}
+
+fn find_head_end(data: &[u8]) -> Option<usize> {
+    data.windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|position| position + 4)
+}
+
+impl<S: AsyncWrite + Unpin> DeflateStream<S> {
+    fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write_ready.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_ready))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_ready.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for DeflateStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if let (State::Passthrough, true) = (&this.state, this.read_ready.is_empty()) {
+            return Pin::new(&mut this.inner).poll_read(cx, buf);
+        }
+        while this.read_ready.is_empty() {
+            let mut chunk = [0u8; READ_CHUNK_BYTES];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // End of stream; an incomplete frame is left for tungstenite to report.
+                this.read_ready.extend_from_slice(&this.read_raw.split());
+                break;
+            }
+            this.read_raw.extend_from_slice(chunk.filled());
+            this.decode()?;
+        }
+        let len = this.read_ready.len().min(buf.remaining());
+        buf.put_slice(&this.read_ready.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for DeflateStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Only ever non-empty with compression on.
+        if this.write_ready.len() >= WRITE_HIGH_WATER_BYTES {
+            ready!(this.poll_drain(cx))?;
+        }
+        match &mut this.state {
+            State::Deflate(codec) => {
+                this.write_raw.extend_from_slice(buf);
+                codec.encode(&mut this.write_raw, &mut this.write_ready)?;
+                Poll::Ready(Ok(buf.len()))
+            }
+            _ => Pin::new(&mut this.inner).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_drain(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(value: Value) -> DeflateOptions {
+        DeflateOptions::from_json(&value).unwrap().unwrap()
+    }
+
+    fn response(extensions: &str) -> Vec<u8> {
+        format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nSec-WebSocket-Extensions: {extensions}\r\n\r\n")
+            .into_bytes()
+    }
+
+    #[test]
+    fn parses_options_and_builds_offer() {
+        assert_eq!(
+            DeflateOptions::from_json(&Value::Bool(false)).unwrap(),
+            None
+        );
+        assert_eq!(
+            options(Value::Bool(true)).offer(),
+            "permessage-deflate; client_max_window_bits"
+        );
+        let options = options(serde_json::json!({
+            "clientMaxWindowBits": 10,
+            "serverMaxWindowBits": 12,
+            "clientNoContextTakeover": true,
+            "serverNoContextTakeover": true,
+        }));
+        assert_eq!(
+            options.offer(),
+            "permessage-deflate; client_max_window_bits=10; server_max_window_bits=12; \
+             client_no_context_takeover; server_no_context_takeover"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        for value in [
+            serde_json::json!("deflate"),
+            serde_json::json!({ "clientMaxWindowBits": 7 }),
+            serde_json::json!({ "serverMaxWindowBits": 16 }),
+            serde_json::json!({ "serverNoContextTakeover": "yes" }),
+        ] {
+            assert!(DeflateOptions::from_json(&value).is_err(), "{value}");
+        }
+    }
+
+    #[test]
+    fn negotiates_parameters_from_response() {
+        let options = options(serde_json::json!({ "clientMaxWindowBits": 12 }));
+        assert_eq!(
+            options.negotiate(&response(
+                "x-other, permessage-deflate; client_max_window_bits=10; server_no_context_takeover"
+            )),
+            Some(Negotiated {
+                client_window_bits: 10,
+                client_no_context_takeover: false,
+                server_no_context_takeover: true,
+            })
+        );
+        assert_eq!(
+            options.negotiate(&response("permessage-deflate")),
+            Some(Negotiated {
+                client_window_bits: 12,
+                client_no_context_takeover: false,
+                server_no_context_takeover: false,
+            })
+        );
+        assert_eq!(options.negotiate(&response("x-other")), None);
+        assert_eq!(options.negotiate(b"HTTP/1.1 403 Forbidden\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn round_trips_messages_with_context_takeover() {
+        let negotiated = || Negotiated {
+            client_window_bits: 15,
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
+        };
+        let mut client = Codec::new(negotiated());
+        let mut server = Codec::new(negotiated());
+        let mask = [1, 2, 3, 4];
+
+        for text in ["repeated payload", "repeated payload", ""] {
+            let mut written = BytesMut::new();
+            FrameHead::write(
+                &mut written,
+                OPCODE_TEXT,
+                false,
+                Some(mask),
+                text.as_bytes(),
+            );
+            let mut compressed = BytesMut::new();
+            client.encode(&mut written, &mut compressed).unwrap();
+            assert_eq!(compressed[0] & 0x40, 0x40);
+
+            let mut decoded = BytesMut::new();
+            server.decode(&mut compressed, &mut decoded).unwrap();
+            let head = FrameHead::parse(&decoded).unwrap().unwrap();
+            assert!(!head.rsv1);
+            assert_eq!(head.payload(&decoded), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn sends_uncompressed_with_eight_bit_window() {
+        let mut codec = Codec::new(Negotiated {
+            client_window_bits: 8,
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
+        });
+        let mut written = BytesMut::new();
+        FrameHead::write(
+            &mut written,
+            OPCODE_TEXT,
+            false,
+            Some([1, 2, 3, 4]),
+            b"text",
+        );
+        let expected = written.clone();
+        let mut output = BytesMut::new();
+        codec.encode(&mut written, &mut output).unwrap();
+        assert_eq!(output, expected);
+    }
+}